@@ -1,8 +1,262 @@
-use reqwest::{Client};
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use reqwest::{Client, ClientBuilder, NoProxy, Proxy, Response};
+use reqwest::redirect::Policy;
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName};
+use rustls::client::{verify_server_name, ServerCertVerified, ServerCertVerifier};
+use rustls::server::ParsedCertificate;
+use rustls_pemfile::Item;
+use sha2::{Digest, Sha256};
+use tokio::time::Instant;
+use crate::config::{HttpConfig, TlsConfig};
+use crate::path_data::UploadError;
 
-pub fn create_client() -> Client {
-    Client::builder()
-        .danger_accept_invalid_certs(true)
+pub fn create_client(tls: &TlsConfig, http: &HttpConfig) -> Result<Client, String> {
+    create_client_builder(tls, http)?
         .build()
-        .unwrap()
+        .map_err(|error| format!("Could not create HTTP client: {}", error))
+}
+
+/// Client that returns redirects instead of following them, for APIs that answer with a redirect
+/// that carries what the caller needs
+pub fn create_client_without_redirects(tls: &TlsConfig, http: &HttpConfig) -> Result<Client, String> {
+    create_client_builder(tls, http)?
+        .redirect(Policy::none())
+        .build()
+        .map_err(|error| format!("Could not create HTTP client: {}", error))
+}
+
+/// Builder with the TLS, proxy and connection settings from the config
+fn create_client_builder(tls: &TlsConfig, http: &HttpConfig) -> Result<ClientBuilder, String> {
+    let tls_config = create_tls_config(tls)?;
+    let mut builder = Client::builder()
+        .use_preconfigured_tls(tls_config)
+        .connect_timeout(Duration::from_secs(http.connect_timeout_secs))
+        // Uploads set their own timeout from the file size, every other request gets the base timeout
+        .timeout(Duration::from_secs(http.base_timeout_secs))
+        .tcp_keepalive(http.tcp_keepalive_secs.map(Duration::from_secs))
+        .pool_idle_timeout(http.pool_idle_timeout_secs.map(Duration::from_secs));
+
+    if let Some(max_idle) = http.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(max_idle);
+    }
+
+    // Without any proxy settings reqwest falls back to the HTTP_PROXY and HTTPS_PROXY variables
+    let no_proxy = http.no_proxy.as_deref().and_then(NoProxy::from_string);
+    if let Some(http_proxy) = &http.http_proxy {
+        let proxy = Proxy::http(http_proxy)
+            .map_err(|error| format!("Invalid http_proxy {}: {}", http_proxy, error))?;
+        builder = builder.proxy(proxy.no_proxy(no_proxy.clone()));
+    }
+    if let Some(https_proxy) = &http.https_proxy {
+        let proxy = Proxy::https(https_proxy)
+            .map_err(|error| format!("Invalid https_proxy {}: {}", https_proxy, error))?;
+        builder = builder.proxy(proxy.no_proxy(no_proxy));
+    }
+
+    Ok(builder)
+}
+
+/// Timeouts for requests that carry file data, where a fixed limit would either cut off large files
+/// or let small ones hang for too long
+#[derive(Debug, Clone)]
+pub struct TransferTimeouts {
+    base: Duration,
+    min_bytes_per_sec: u64,
+    /// How long a transfer may go without sending anything before it is given up
+    pub idle: Option<Duration>,
+}
+
+impl TransferTimeouts {
+    pub fn from_config(http: &HttpConfig) -> TransferTimeouts {
+        TransferTimeouts {
+            base: Duration::from_secs(http.base_timeout_secs),
+            min_bytes_per_sec: http.min_throughput_kbps.max(1) * 1024,
+            idle: http.read_timeout_secs.map(Duration::from_secs),
+        }
+    }
+
+    /// Total time allowed for a request that sends this many bytes
+    pub fn for_size(&self, bytes: u64) -> Duration {
+        self.base + Duration::from_secs(bytes / self.min_bytes_per_sec)
+    }
+}
+
+/// Last time a transfer made progress, or `None` once everything has been sent
+#[derive(Clone)]
+pub struct Activity(Arc<Mutex<Option<Instant>>>);
+
+impl Activity {
+    pub fn new() -> Activity {
+        Activity(Arc::new(Mutex::new(Some(Instant::now()))))
+    }
+
+    pub fn touch(&self) {
+        let mut last = self.0.lock().unwrap();
+        if last.is_some() {
+            *last = Some(Instant::now());
+        }
+    }
+
+    /// Marks the body as fully sent. The server may take long to respond after that on large
+    /// files, which is left to the total timeout of the request.
+    pub fn finish(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+
+    fn last(&self) -> Option<Instant> {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Waits for the request, but gives up once `activity` has not been touched for the idle timeout
+/// while the body is still being sent
+pub async fn send_with_idle_timeout(
+    request: impl Future<Output = Result<Response, reqwest::Error>>,
+    activity: &Activity,
+    idle: Option<Duration>,
+) -> Result<Response, UploadError> {
+    let Some(idle) = idle else {
+        return Ok(request.await?);
+    };
+    tokio::pin!(request);
+    while let Some(last) = activity.last() {
+        tokio::select! {
+            result = &mut request => return Ok(result?),
+            _ = tokio::time::sleep_until(last + idle) => {
+                if activity.last().is_some_and(|last| last.elapsed() >= idle) {
+                    return Err(UploadError::Stalled);
+                }
+            }
+        }
+    }
+    Ok(request.await?)
+}
+
+fn create_tls_config(tls: &TlsConfig) -> Result<ClientConfig, String> {
+    let mut root_store = RootCertStore::empty();
+    root_store.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+    }));
+    if let Some(ca_bundle) = &tls.ca_bundle {
+        for certificate in read_certificates(ca_bundle)? {
+            root_store
+                .add(&certificate)
+                .map_err(|error| format!("Invalid certificate in {}: {}", ca_bundle, error))?;
+        }
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_store);
+
+    let mut tls_config = match (&tls.client_certificate, &tls.client_key) {
+        (Some(certificate), Some(key)) => builder
+            .with_client_auth_cert(read_certificates(certificate)?, read_private_key(key)?)
+            .map_err(|error| format!("Invalid client certificate or key: {}", error))?,
+        (None, None) => builder.with_no_client_auth(),
+        _ => return Err(String::from("Both client_certificate and client_key must be set for mTLS")),
+    };
+
+    if tls.accept_invalid_certificates {
+        tls_config.dangerous().set_certificate_verifier(Arc::new(AcceptAnyCertificate));
+    } else if !tls.pinned_sha256.is_empty() {
+        let pins = tls.pinned_sha256
+            .iter()
+            .map(|pin| pin.replace(':', "").to_lowercase())
+            .collect();
+        tls_config.dangerous().set_certificate_verifier(Arc::new(PinnedCertificate { pins }));
+    }
+
+    Ok(tls_config)
+}
+
+fn read_certificates(path: &str) -> Result<Vec<Certificate>, String> {
+    let file = File::open(path).map_err(|error| format!("Could not open {}: {}", path, error))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|error| format!("Could not read certificates from {}: {}", path, error))?;
+    if certificates.is_empty() {
+        return Err(format!("No certificates found in {}", path));
+    }
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+fn read_private_key(path: &str) -> Result<PrivateKey, String> {
+    let file = File::open(path).map_err(|error| format!("Could not open {}: {}", path, error))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .map_err(|error| format!("Could not read private key from {}: {}", path, error))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("No private key found in {}", path))
+}
+
+/// Trusts a server whose own certificate has one of the pinned SHA-256 fingerprints and is valid for
+/// its name, whether or not a known CA signed it. Only the end entity counts, as the server proves it
+/// holds that key during the handshake, while any public certificate can be appended to a chain.
+struct PinnedCertificate {
+    pins: Vec<String>,
+}
+
+impl PinnedCertificate {
+    fn is_pinned(&self, certificate: &Certificate) -> bool {
+        let fingerprint = format!("{:x}", Sha256::digest(&certificate.0));
+        self.pins.contains(&fingerprint)
+    }
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if !self.is_pinned(end_entity) {
+            return Err(rustls::Error::General(String::from("Server certificate does not match any pinned fingerprint")));
+        }
+        verify_server_name(&ParsedCertificate::try_from(end_entity)?, server_name)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Only used when `accept_invalid_certificates` is explicitly turned on
+struct AcceptAnyCertificate;
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// The MediaCMS server address, taken from everything in `API_URL` before `/api/`
+pub fn base_url() -> String {
+    let url = env::var("API_URL").expect("API_URL must be set");
+    match url.find("/api/") {
+        Some(index) => url[..index].to_string(),
+        None => url.trim_end_matches('/').to_string(),
+    }
+}
+
+/// Pages on the MediaCMS website send anyone who is not logged in to the login page
+pub fn redirected_to_login(response: &Response) -> bool {
+    response.url().path().contains("/accounts/login")
 }
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::ops::Add;
+use reqwest::{Client, RequestBuilder, StatusCode};
+use reqwest::header::{HeaderMap, COOKIE, REFERER, SET_COOKIE};
+use serde::{Deserialize, Serialize};
+use crate::api;
+use crate::config::AuthConfig;
+use crate::secrets::{create_secret_provider, Secret, SecretProvider};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    /// HTTP basic auth with `[USERNAME]_PASSWORD`
+    Basic,
+    /// MediaCMS API token from `[USERNAME]_TOKEN`
+    Token,
+    /// Logs in once with `[USERNAME]_PASSWORD` and reuses the session cookie
+    Session,
+}
+
+#[derive(Debug, Clone)]
+pub enum Credentials {
+    Basic { username: String, password: Secret },
+    Token(Secret),
+    Session { session_id: Secret, csrf_token: Secret },
+}
+
+impl Credentials {
+    /// Adds the authentication of this user to a request
+    pub fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        match self {
+            Credentials::Basic { username, password } => request.basic_auth(username, Some(password.expose())),
+            Credentials::Token(token) => request.header("Authorization", format!("Token {}", token.expose())),
+            Credentials::Session { session_id, csrf_token } => request
+                .header(COOKIE, format!("sessionid={}; csrftoken={}", session_id.expose(), csrf_token.expose()))
+                .header("X-CSRFToken", csrf_token.expose())
+                .header(REFERER, api::base_url()),
+        }
+    }
+}
+
+/// Credentials for every user that can upload, resolved and checked once at startup
+pub struct CredentialStore {
+    credentials: HashMap<String, Credentials>,
+}
+
+impl CredentialStore {
+    /// The client must not follow redirects, as the session cookie comes with the redirect after the login
+    pub async fn resolve(client: &Client, config: &AuthConfig, usernames: &[String]) -> Result<CredentialStore, String> {
+        let secrets = create_secret_provider(&config.secret_source);
+        let mut credentials = HashMap::new();
+        for username in usernames {
+            let method = config.method_for(username);
+            let user_credentials = match method {
+                AuthMethod::Basic => Credentials::Basic {
+                    username: username.clone(),
+                    password: read_secret(secrets.as_ref(), username, "_PASSWORD")?,
+                },
+                AuthMethod::Token => Credentials::Token(read_secret(secrets.as_ref(), username, "_TOKEN")?),
+                AuthMethod::Session => {
+                    let password = read_secret(secrets.as_ref(), username, "_PASSWORD")?;
+                    login(client, config, username, &password).await?
+                }
+            };
+            verify(client, username, &user_credentials).await?;
+            credentials.insert(username.clone(), user_credentials);
+        }
+        Ok(CredentialStore { credentials })
+    }
+
+    pub fn get(&self, username: &str) -> &Credentials {
+        // Every user that read_file can produce was resolved at startup
+        &self.credentials[username]
+    }
+}
+
+fn read_secret(secrets: &dyn SecretProvider, username: &str, suffix: &str) -> Result<Secret, String> {
+    let name = username.to_uppercase().add(suffix);
+    secrets.get(&name).map_err(|error| format!("No secret for user {}: {}", username, error))
+}
+
+/// Logs in through the MediaCMS login form and keeps the session and CSRF cookies
+async fn login(client: &Client, config: &AuthConfig, username: &str, password: &Secret) -> Result<Credentials, String> {
+    let login_url = config
+        .login_url
+        .clone()
+        .unwrap_or_else(|| format!("{}/accounts/login/", api::base_url()));
+
+    let login_page = client
+        .get(&login_url)
+        .send()
+        .await
+        .map_err(|error| format!("Could not reach login page for {}: {}", username, error))?;
+    let csrf_token = find_cookie(login_page.headers(), "csrftoken")
+        .ok_or_else(|| format!("Login page did not set a CSRF token for {}", username))?;
+
+    let response = client
+        .post(&login_url)
+        .header(COOKIE, format!("csrftoken={}", csrf_token))
+        .header(REFERER, &login_url)
+        .form(&[
+            ("login", username),
+            ("password", password.expose()),
+            ("csrfmiddlewaretoken", csrf_token.as_str()),
+        ])
+        .send()
+        .await
+        .map_err(|error| format!("Could not log in {}: {}", username, error))?;
+
+    session_from_login(response.status(), response.headers(), csrf_token)
+        .ok_or_else(|| format!("Login failed for {} with status {}", username, response.status()))
+}
+
+/// The session of a successful login, which Django answers with a redirect. A failed login shows the
+/// form again, without a session cookie.
+fn session_from_login(status: StatusCode, headers: &HeaderMap, csrf_token: String) -> Option<Credentials> {
+    if !status.is_success() && !status.is_redirection() {
+        return None;
+    }
+    let session_id = find_cookie(headers, "sessionid")?;
+    // Django rotates the CSRF token on login
+    let csrf_token = find_cookie(headers, "csrftoken").unwrap_or(csrf_token);
+    Some(Credentials::Session {
+        session_id: Secret::new(session_id),
+        csrf_token: Secret::new(csrf_token),
+    })
+}
+
+/// Makes sure MediaCMS accepts the credentials before any upload relies on them
+async fn verify(client: &Client, username: &str, credentials: &Credentials) -> Result<(), String> {
+    let url = format!("{}/api/v1/whoami", api::base_url());
+    let response = credentials
+        .apply(client.get(url))
+        .send()
+        .await
+        .map_err(|error| format!("Could not verify credentials for {}: {}", username, error))?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("Credentials for {} were rejected with status {}", username, response.status()))
+    }
+}
+
+fn find_cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .filter_map(|value| value.split(';').next())
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| key.trim() == name)
+        .map(|(_, value)| value.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+    use super::*;
+
+    fn cookies(values: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(SET_COOKIE, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn session_is_read_from_login_redirect() {
+        let headers = cookies(&[
+            "csrftoken=rotated; expires=Sat, 16 Oct 2027 00:00:00 GMT; Max-Age=31449600; Path=/; SameSite=Lax",
+            "sessionid=abc123; expires=Sat, 01 Nov 2026 00:00:00 GMT; HttpOnly; Max-Age=1209600; Path=/; SameSite=Lax",
+        ]);
+        match session_from_login(StatusCode::FOUND, &headers, String::from("initial")) {
+            Some(Credentials::Session { session_id, csrf_token }) => {
+                assert_eq!(session_id.expose(), "abc123");
+                assert_eq!(csrf_token.expose(), "rotated");
+            }
+            other => panic!("Expected a session, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn csrf_token_is_kept_if_not_rotated() {
+        let headers = cookies(&["sessionid=abc123; Path=/"]);
+        match session_from_login(StatusCode::FOUND, &headers, String::from("initial")) {
+            Some(Credentials::Session { csrf_token, .. }) => assert_eq!(csrf_token.expose(), "initial"),
+            other => panic!("Expected a session, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn failed_login_has_no_session() {
+        let form_again = cookies(&["csrftoken=rotated; Path=/"]);
+        assert!(session_from_login(StatusCode::OK, &form_again, String::from("initial")).is_none());
+        let rejected = cookies(&["sessionid=abc123; Path=/"]);
+        assert!(session_from_login(StatusCode::FORBIDDEN, &rejected, String::from("initial")).is_none());
+    }
+}
@@ -0,0 +1,322 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use chrono::{DateTime, Datelike, Local, NaiveTime, Weekday};
+use tokio::time::Instant;
+use crate::api::Activity;
+use crate::config::{BandwidthConfig, BandwidthRule};
+
+/// Longest single sleep while waiting for bandwidth, so schedule changes are picked up quickly
+const MAX_WAIT: Duration = Duration::from_secs(1);
+/// Rate for data that is already being sent during a pause, if the schedule has no limit at all
+const PAUSED_FALLBACK_RATE: f64 = 1024.0 * 1024.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BandwidthLimit {
+    Unlimited,
+    /// Bytes per second
+    Limited(f64),
+    Paused,
+}
+
+impl BandwidthLimit {
+    fn from_rule(limit_mb_per_sec: Option<f64>, paused: bool) -> BandwidthLimit {
+        match (paused, limit_mb_per_sec) {
+            (true, _) => BandwidthLimit::Paused,
+            (false, Some(limit)) if limit > 0.0 => BandwidthLimit::Limited(limit * 1024.0 * 1024.0),
+            (false, _) => BandwidthLimit::Unlimited,
+        }
+    }
+}
+
+impl Display for BandwidthLimit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BandwidthLimit::Unlimited => write!(f, "unlimited"),
+            BandwidthLimit::Limited(rate) => write!(f, "{:.1} MB/s", rate / (1024.0 * 1024.0)),
+            BandwidthLimit::Paused => write!(f, "paused"),
+        }
+    }
+}
+
+struct Window {
+    days: Vec<Weekday>,
+    from: NaiveTime,
+    to: NaiveTime,
+    limit: BandwidthLimit,
+}
+
+impl Window {
+    fn from_rule(rule: &BandwidthRule) -> Result<Window, String> {
+        let parse_time = |time: &str| {
+            NaiveTime::parse_from_str(time, "%H:%M")
+                .map_err(|_| format!("Invalid time {} in bandwidth schedule, expected HH:MM", time))
+        };
+        let days = rule.days
+            .iter()
+            .map(|day| Weekday::from_str(day).map_err(|_| format!("Invalid day {} in bandwidth schedule", day)))
+            .collect::<Result<Vec<Weekday>, String>>()?;
+        Ok(Window {
+            days,
+            from: parse_time(&rule.from)?,
+            to: parse_time(&rule.to)?,
+            limit: BandwidthLimit::from_rule(rule.limit_mb_per_sec, rule.paused),
+        })
+    }
+
+    fn contains(&self, now: &DateTime<Local>) -> bool {
+        let time = now.time();
+        let started_on = if self.from <= self.to {
+            if time < self.from || time >= self.to {
+                return false;
+            }
+            now.weekday()
+        } else if time >= self.from {
+            now.weekday()
+        } else if time < self.to {
+            // The window goes past midnight, and its days are the days it starts on
+            now.weekday().pred()
+        } else {
+            return false;
+        };
+        self.days.is_empty() || self.days.contains(&started_on)
+    }
+}
+
+/// Which limit applies at what time of day. The first matching window wins, otherwise the default applies.
+pub struct BandwidthSchedule {
+    default: BandwidthLimit,
+    windows: Vec<Window>,
+}
+
+impl BandwidthSchedule {
+    pub fn from_config(config: &BandwidthConfig) -> Result<BandwidthSchedule, String> {
+        Ok(BandwidthSchedule {
+            default: BandwidthLimit::from_rule(config.limit_mb_per_sec, false),
+            windows: config.schedule
+                .iter()
+                .map(Window::from_rule)
+                .collect::<Result<Vec<Window>, String>>()?,
+        })
+    }
+
+    /// The lowest rate anywhere in the schedule
+    pub fn lowest_rate(&self) -> Option<f64> {
+        self.windows
+            .iter()
+            .map(|window| window.limit)
+            .chain([self.default])
+            .filter_map(|limit| match limit {
+                BandwidthLimit::Limited(rate) => Some(rate),
+                _ => None,
+            })
+            .reduce(f64::min)
+    }
+
+    pub fn limit_at(&self, now: &DateTime<Local>) -> BandwidthLimit {
+        self.windows
+            .iter()
+            .find(|window| window.contains(now))
+            .map(|window| window.limit)
+            .unwrap_or(self.default)
+    }
+}
+
+struct Bucket {
+    limit: BandwidthLimit,
+    /// Rate that data already being sent is held to while paused. The last limit before the pause,
+    /// or else the lowest one in the schedule.
+    paused_rate: f64,
+    /// Bytes that may be sent right away. Goes negative when a chunk is larger than what is available.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket shared by every upload, so the limit applies to the total outgoing bandwidth
+pub struct BandwidthLimiter {
+    bucket: Mutex<Bucket>,
+    /// Times an upload was held back, so uploads slowed by the limit can be told from a slow server
+    waits: AtomicU64,
+}
+
+impl BandwidthLimiter {
+    pub fn new(limit: BandwidthLimit, lowest_rate: Option<f64>) -> BandwidthLimiter {
+        let paused_rate = match limit {
+            BandwidthLimit::Limited(rate) => rate,
+            _ => lowest_rate.unwrap_or(PAUSED_FALLBACK_RATE),
+        };
+        BandwidthLimiter {
+            bucket: Mutex::new(Bucket {
+                limit,
+                paused_rate,
+                tokens: 0.0,
+                last_refill: Instant::now(),
+            }),
+            waits: AtomicU64::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> BandwidthLimit {
+        self.bucket.lock().unwrap().limit
+    }
+
+    pub fn set_limit(&self, limit: BandwidthLimit) {
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.limit != limit {
+            if let BandwidthLimit::Limited(rate) = limit {
+                bucket.paused_rate = rate;
+            }
+            bucket.limit = limit;
+            bucket.tokens = 0.0;
+            bucket.last_refill = Instant::now();
+        }
+    }
+
+    /// Waits until uploads are not paused
+    /// How often uploads have been held back so far
+    pub fn wait_count(&self) -> u64 {
+        self.waits.load(Ordering::Relaxed)
+    }
+
+    pub async fn wait_until_unpaused(&self) {
+        while self.limit() == BandwidthLimit::Paused {
+            self.waits.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(MAX_WAIT).await;
+        }
+    }
+
+    /// Waits until `bytes` may be sent. The activity is kept alive while waiting, as the upload is
+    /// held back on purpose and has not stalled. An open request can not wait out a whole pause, so
+    /// data that is already being sent goes on at the paused rate, and only `wait_until_unpaused`
+    /// holds back new uploads and chunks.
+    pub async fn consume(&self, bytes: usize, activity: Option<&Activity>) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let rate = match bucket.limit {
+                    BandwidthLimit::Unlimited => return,
+                    BandwidthLimit::Limited(rate) => rate,
+                    BandwidthLimit::Paused => bucket.paused_rate,
+                };
+                let now = Instant::now();
+                let refill = now.duration_since(bucket.last_refill).as_secs_f64() * rate;
+                // Allow bursts of up to one second
+                bucket.tokens = (bucket.tokens + refill).min(rate);
+                bucket.last_refill = now;
+                if bucket.tokens >= 0.0 {
+                    bucket.tokens -= bytes as f64;
+                    return;
+                }
+                Duration::from_secs_f64(-bucket.tokens / rate).min(MAX_WAIT)
+            };
+            self.waits.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(wait).await;
+            if let Some(activity) = activity {
+                activity.touch();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use super::*;
+
+    fn window(days: &[&str], from: &str, to: &str) -> Window {
+        Window::from_rule(&BandwidthRule {
+            days: days.iter().map(|day| day.to_string()).collect(),
+            from: from.to_string(),
+            to: to.to_string(),
+            limit_mb_per_sec: Some(1.0),
+            paused: false,
+        }).unwrap()
+    }
+
+    /// 2024-01-01 was a Monday
+    fn monday_at(hour: u32, minute: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 1, 1, hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn window_contains_its_start_but_not_its_end() {
+        let window = window(&[], "09:00", "17:00");
+        assert!(!window.contains(&monday_at(8, 59)));
+        assert!(window.contains(&monday_at(9, 0)));
+        assert!(window.contains(&monday_at(16, 59)));
+        assert!(!window.contains(&monday_at(17, 0)));
+    }
+
+    #[test]
+    fn window_can_go_past_midnight() {
+        let window = window(&[], "22:00", "06:00");
+        assert!(window.contains(&monday_at(23, 0)));
+        assert!(window.contains(&monday_at(0, 0)));
+        assert!(window.contains(&monday_at(5, 59)));
+        assert!(!window.contains(&monday_at(6, 0)));
+        assert!(!window.contains(&monday_at(12, 0)));
+    }
+
+    #[test]
+    fn window_only_applies_on_its_days() {
+        assert!(window(&["mon", "tue"], "00:00", "23:59").contains(&monday_at(12, 0)));
+        assert!(!window(&["sat", "sun"], "00:00", "23:59").contains(&monday_at(12, 0)));
+    }
+
+    #[test]
+    fn window_past_midnight_applies_on_the_day_it_starts() {
+        let window = window(&["fri"], "22:00", "06:00");
+        // 2024-01-05 was a Friday
+        let at = |day: u32, hour: u32| Local.with_ymd_and_hms(2024, 1, day, hour, 0, 0).unwrap();
+        assert!(window.contains(&at(5, 23)));
+        assert!(window.contains(&at(6, 1)));
+        assert!(!window.contains(&at(5, 1)));
+        assert!(!window.contains(&at(6, 23)));
+    }
+
+    #[test]
+    fn pause_keeps_the_last_limit_for_data_being_sent() {
+        let limiter = BandwidthLimiter::new(BandwidthLimit::Limited(2.0), Some(1.0));
+        limiter.set_limit(BandwidthLimit::Paused);
+        assert_eq!(limiter.bucket.lock().unwrap().paused_rate, 2.0);
+
+        let limiter = BandwidthLimiter::new(BandwidthLimit::Unlimited, Some(1.0));
+        limiter.set_limit(BandwidthLimit::Paused);
+        assert_eq!(limiter.bucket.lock().unwrap().paused_rate, 1.0);
+    }
+
+    #[test]
+    fn lowest_rate_of_schedule() {
+        let rule = |limit_mb_per_sec: Option<f64>, paused: bool| BandwidthRule {
+            days: vec![],
+            from: String::from("08:00"),
+            to: String::from("17:00"),
+            limit_mb_per_sec,
+            paused,
+        };
+        let config = BandwidthConfig {
+            limit_mb_per_sec: Some(10.0),
+            schedule: vec![rule(Some(2.0), false), rule(None, true), rule(None, false)],
+        };
+        let schedule = BandwidthSchedule::from_config(&config).unwrap();
+        assert_eq!(schedule.lowest_rate(), Some(2.0 * 1024.0 * 1024.0));
+
+        let config = BandwidthConfig { limit_mb_per_sec: None, schedule: vec![rule(None, true)] };
+        assert_eq!(BandwidthSchedule::from_config(&config).unwrap().lowest_rate(), None);
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        let rule = |days: Vec<String>, from: &str| BandwidthRule {
+            days,
+            from: from.to_string(),
+            to: String::from("10:00"),
+            limit_mb_per_sec: None,
+            paused: false,
+        };
+        assert!(Window::from_rule(&rule(vec![], "9 o'clock")).is_err());
+        assert!(Window::from_rule(&rule(vec![String::from("someday")], "09:00")).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use regex::Regex;
+use crate::config::{CategoryConfig, CategoryRule};
+
+struct Rule {
+    depth: Option<usize>,
+    pattern: Option<Regex>,
+    category: Option<String>,
+}
+
+impl Rule {
+    fn from_config(rule: &CategoryRule) -> Result<Rule, String> {
+        let pattern = match &rule.pattern {
+            Some(pattern) => Some(
+                Regex::new(pattern).map_err(|error| format!("Invalid category pattern {}: {}", pattern, error))?,
+            ),
+            None => None,
+        };
+        Ok(Rule {
+            depth: rule.depth,
+            pattern,
+            category: rule.category.clone(),
+        })
+    }
+
+    /// The category of the folder at `depth`, if this rule applies to it
+    fn category_for(&self, depth: usize, folder: &str) -> Option<String> {
+        if self.depth.is_some_and(|rule_depth| rule_depth != depth) {
+            return None;
+        }
+        if self.pattern.as_ref().is_some_and(|pattern| !pattern.is_match(folder)) {
+            return None;
+        }
+        Some(self.category.clone().unwrap_or_else(|| folder.to_string()))
+    }
+}
+
+/// Turns the folders of a file into MediaCMS categories. The first rule that applies to a folder
+/// decides its category.
+pub struct CategoryRules {
+    rules: Vec<Rule>,
+}
+
+impl CategoryRules {
+    pub fn from_config(config: &CategoryConfig) -> Result<CategoryRules, String> {
+        Ok(CategoryRules {
+            rules: config.rules
+                .iter()
+                .map(Rule::from_config)
+                .collect::<Result<Vec<Rule>, String>>()?,
+        })
+    }
+
+    /// Categories for the folders below the user folder, outermost first
+    pub fn categories_for(&self, folders: &[&str]) -> Vec<String> {
+        let mut categories: Vec<String> = Vec::new();
+        for (index, folder) in folders.iter().enumerate() {
+            let category = self.rules
+                .iter()
+                .find_map(|rule| rule.category_for(index + 1, folder));
+            if let Some(category) = category {
+                if !categories.contains(&category) {
+                    categories.push(category);
+                }
+            }
+        }
+        categories
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: Vec<CategoryRule>) -> CategoryRules {
+        CategoryRules::from_config(&CategoryConfig { create_missing: false, rules }).unwrap()
+    }
+
+    fn rule(depth: Option<usize>, pattern: Option<&str>, category: Option<&str>) -> CategoryRule {
+        CategoryRule {
+            depth,
+            pattern: pattern.map(String::from),
+            category: category.map(String::from),
+        }
+    }
+
+    #[test]
+    fn folders_at_depth_become_categories() {
+        let rules = rules(vec![rule(Some(1), None, None)]);
+        assert_eq!(rules.categories_for(&["Holidays", "2023"]), vec!["Holidays"]);
+        assert!(rules.categories_for(&[]).is_empty());
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let rules = rules(vec![
+            rule(None, Some("^\\d{4}$"), Some("By year")),
+            rule(Some(2), None, Some("Second level")),
+            rule(None, None, None),
+        ]);
+        assert_eq!(
+            rules.categories_for(&["Holidays", "2023", "Beach"]),
+            vec!["Holidays", "By year", "Beach"]
+        );
+        assert_eq!(rules.categories_for(&["Holidays", "Beach"]), vec!["Holidays", "Second level"]);
+    }
+
+    #[test]
+    fn categories_are_not_repeated() {
+        let rules = rules(vec![rule(None, None, Some("Videos"))]);
+        assert_eq!(rules.categories_for(&["a", "b", "c"]), vec!["Videos"]);
+    }
+
+    #[test]
+    fn invalid_pattern_is_rejected() {
+        let config = CategoryConfig { create_missing: false, rules: vec![rule(None, Some("("), None)] };
+        assert!(CategoryRules::from_config(&config).is_err());
+    }
+}
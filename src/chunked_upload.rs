@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use reqwest::{Client, multipart};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use crate::api::TransferTimeouts;
+use crate::auth::Credentials;
+use crate::bandwidth::BandwidthLimiter;
+use crate::config::ChunkedUploadConfig;
+use crate::hash_cache::FileStamp;
+use crate::path_data::{PathData, UploadError};
+
+/// Progress of a single file sent through the fine-uploader chunk endpoint.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChunkProgress {
+    pub uuid: String,
+    pub file_size: u64,
+    /// The file as it was when the first chunk was sent. Missing in progress from earlier versions.
+    #[serde(default)]
+    pub stamp: Option<FileStamp>,
+    pub chunk_size: u64,
+    pub total_parts: u64,
+    /// Index of the first chunk that has not been acknowledged by the server
+    pub next_part: u64,
+}
+
+/// Chunk progress for every unfinished upload, keyed by absolute path and mirrored to disk after
+/// each acknowledged chunk so an interrupted upload can resume after a restart.
+pub struct ChunkProgressStore {
+    file_path: String,
+    entries: Mutex<HashMap<String, ChunkProgress>>,
+}
+
+impl ChunkProgressStore {
+    pub fn load(file_path: &str) -> ChunkProgressStore {
+        let entries = fs::read_to_string(file_path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        ChunkProgressStore {
+            file_path: file_path.to_string(),
+            entries: Mutex::new(entries),
+        }
+    }
+
+    fn get(&self, path: &str) -> Option<ChunkProgress> {
+        self.entries.lock().unwrap().get(path).cloned()
+    }
+
+    fn set(&self, path: &str, progress: ChunkProgress) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(path.to_string(), progress);
+        self.save(&entries);
+    }
+
+    fn remove(&self, path: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.remove(path).is_some() {
+            self.save(&entries);
+        }
+    }
+
+    fn save(&self, entries: &HashMap<String, ChunkProgress>) {
+        if let Ok(serialized) = serde_json::to_string_pretty(entries) {
+            // Losing a progress write only means re-sending a chunk, so failures are not fatal
+            let _ = fs::write(&self.file_path, serialized);
+        }
+    }
+}
+
+pub struct ChunkedUploader {
+    url: String,
+    done_url: String,
+    chunk_size: u64,
+    progress: ChunkProgressStore,
+}
+
+impl ChunkedUploader {
+    pub fn new(config: &ChunkedUploadConfig) -> ChunkedUploader {
+        ChunkedUploader {
+            url: config.url.clone(),
+            done_url: format!("{}?done", config.url),
+            chunk_size: config.chunk_size_mb.max(1) * 1024 * 1024,
+            progress: ChunkProgressStore::load(&config.progress_file),
+        }
+    }
+
+    pub async fn upload(
+        &self,
+        data: &PathData,
+        client: &Client,
+        credentials: &Credentials,
+        timeouts: &TransferTimeouts,
+        bandwidth: &BandwidthLimiter,
+        sent_bytes: &AtomicU64,
+    ) -> Result<String, UploadError> {
+        let stamp = FileStamp::read(Path::new(&data.absolute_path))?;
+        let progress = match self.progress.get(&data.absolute_path) {
+            // Only resume if the file is unchanged since the chunks were sent
+            Some(progress) if progress.stamp.as_ref() == Some(&stamp) => progress,
+            _ => {
+                let total_parts = data.file_size.div_ceil(self.chunk_size).max(1);
+                ChunkProgress {
+                    uuid: generate_uuid(&data.absolute_path),
+                    file_size: data.file_size,
+                    stamp: Some(stamp),
+                    chunk_size: self.chunk_size,
+                    total_parts,
+                    next_part: 0,
+                }
+            }
+        };
+
+        // Chunks acknowledged in an earlier attempt count as sent
+        sent_bytes.store((progress.next_part * progress.chunk_size).min(progress.file_size), Ordering::Relaxed);
+
+        let mut file = File::open(&data.absolute_path).await?;
+
+        for part_index in progress.next_part..progress.total_parts {
+            // The chunks that were sent are kept, so a pause can start between two of them
+            bandwidth.wait_until_unpaused().await;
+            let offset = part_index * progress.chunk_size;
+            let length = progress.chunk_size.min(progress.file_size - offset);
+
+            file.seek(SeekFrom::Start(offset)).await?;
+            let mut chunk = vec![0; length as usize];
+            file.read_exact(&mut chunk).await?;
+
+            bandwidth.consume(chunk.len(), None).await;
+
+            let chunk_part = multipart::Part::bytes(chunk)
+                .file_name(data.filename.clone())
+                .mime_str("application/octet-stream")?;
+
+            let form = multipart::Form::new()
+                .text("qquuid", progress.uuid.clone())
+                .text("qqfilename", data.filename.clone())
+                .text("qqtotalfilesize", progress.file_size.to_string())
+                .text("qqpartindex", part_index.to_string())
+                .text("qqpartbyteoffset", offset.to_string())
+                .text("qqchunksize", length.to_string())
+                .text("qqtotalparts", progress.total_parts.to_string())
+                .part("qqfile", chunk_part);
+
+            let response = credentials
+                .apply(client.post(&self.url))
+                .timeout(timeouts.for_size(length))
+                .multipart(form)
+                .send()
+                .await?;
+
+            if !response.status().is_success() {
+                return Err(UploadError::Rejected(response.status().as_u16()));
+            }
+
+            sent_bytes.fetch_add(length, Ordering::Relaxed);
+            self.progress.set(&data.absolute_path, ChunkProgress {
+                next_part: part_index + 1,
+                ..progress.clone()
+            });
+        }
+
+        let form = multipart::Form::new()
+            .text("qquuid", progress.uuid.clone())
+            .text("qqfilename", data.filename.clone())
+            .text("qqtotalfilesize", progress.file_size.to_string())
+            .text("qqtotalparts", progress.total_parts.to_string());
+
+        let response = credentials
+            .apply(client.post(&self.done_url))
+            .multipart(form)
+            .send()
+            .await?;
+
+        if response.status().is_client_error() {
+            // The server no longer knows the chunks, so start over on the next attempt
+            self.progress.remove(&data.absolute_path);
+        }
+        if !response.status().is_success() {
+            return Err(UploadError::Rejected(response.status().as_u16()));
+        }
+        self.progress.remove(&data.absolute_path);
+
+        let body: Value = response.json().await?;
+        body["media_url"]
+            .as_str()
+            .and_then(|url| url.split("m=").nth(1))
+            .map(|token| token.to_string())
+            .ok_or(UploadError::MissingMedia)
+    }
+}
+
+/// Builds a UUID formatted identifier from the path and current time, as the endpoint expects one
+/// per upload
+fn generate_uuid(path: &str) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+    let digest = format!("{:x}", md5::compute(format!("{}{}", path, nanos)));
+    format!(
+        "{}-{}-{}-{}-{}",
+        &digest[0..8],
+        &digest[8..12],
+        &digest[12..16],
+        &digest[16..20],
+        &digest[20..32]
+    )
+}
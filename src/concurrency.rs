@@ -0,0 +1,173 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use crate::config::AdaptiveConcurrencyConfig;
+
+/// Number of uploads used to learn the normal upload speed before latency spikes are acted on
+const WARMUP_SAMPLES: u32 = 5;
+/// Weight of a new sample in the moving average of the upload speed
+const LATENCY_SMOOTHING: f64 = 0.2;
+
+struct LimiterState {
+    limit: usize,
+    /// Permits that should be retired instead of returned, because the limit was lowered while they were in use
+    pending_shrink: usize,
+    successes_since_increase: usize,
+    last_decrease: Option<Instant>,
+    /// Moving average of seconds spent per MB uploaded
+    baseline_secs_per_mb: Option<f64>,
+    samples: u32,
+}
+
+/// Limits the number of concurrent uploads using additive increase, multiplicative decrease: the
+/// limit grows by one after a full round of successful uploads, and is halved when the server
+/// signals that it is overloaded.
+pub struct AdaptiveLimiter {
+    semaphore: Arc<Semaphore>,
+    state: Mutex<LimiterState>,
+    min_limit: usize,
+    max_limit: usize,
+    latency_factor: f64,
+    cooldown: Duration,
+}
+
+pub struct AdaptivePermit<'a> {
+    permit: Option<OwnedSemaphorePermit>,
+    limiter: &'a AdaptiveLimiter,
+}
+
+impl Drop for AdaptivePermit<'_> {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        if let Some(permit) = self.permit.take() {
+            if state.pending_shrink > 0 {
+                state.pending_shrink -= 1;
+                permit.forget();
+            }
+        }
+    }
+}
+
+impl AdaptiveLimiter {
+    /// A limiter that never changes, used when adaptive concurrency is turned off
+    pub fn fixed(limit: usize) -> AdaptiveLimiter {
+        AdaptiveLimiter::new(limit, limit, limit, 0.0, Duration::ZERO)
+    }
+
+    pub fn from_config(number_of_threads: usize, config: &AdaptiveConcurrencyConfig) -> AdaptiveLimiter {
+        let min_limit = config.min_threads.max(1);
+        let max_limit = config.max_threads.unwrap_or(number_of_threads).max(min_limit);
+        AdaptiveLimiter::new(
+            number_of_threads.clamp(min_limit, max_limit),
+            min_limit,
+            max_limit,
+            config.latency_factor,
+            Duration::from_secs(config.cooldown_secs),
+        )
+    }
+
+    fn new(initial: usize, min_limit: usize, max_limit: usize, latency_factor: f64, cooldown: Duration) -> AdaptiveLimiter {
+        let initial = initial.max(1);
+        AdaptiveLimiter {
+            semaphore: Arc::new(Semaphore::new(initial)),
+            state: Mutex::new(LimiterState {
+                limit: initial,
+                pending_shrink: 0,
+                successes_since_increase: 0,
+                last_decrease: None,
+                baseline_secs_per_mb: None,
+                samples: 0,
+            }),
+            min_limit: min_limit.max(1),
+            max_limit: max_limit.max(1),
+            latency_factor,
+            cooldown,
+        }
+    }
+
+    pub async fn acquire(&self) -> AdaptivePermit<'_> {
+        let permit = self.semaphore.clone().acquire_owned().await.unwrap();
+        AdaptivePermit {
+            permit: Some(permit),
+            limiter: self,
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    pub fn max_limit(&self) -> usize {
+        self.max_limit
+    }
+
+    /// Records a successful upload, and treats it as overload if it was far slower than usual. The
+    /// time is `None` for uploads that were held back by the bandwidth limit, as it says nothing
+    /// about the server then.
+    pub fn on_success(&self, elapsed: Option<Duration>, bytes: u64) {
+        let Some(elapsed) = elapsed else {
+            self.increase();
+            return;
+        };
+        let megabytes = (bytes as f64 / (1024.0 * 1024.0)).max(1.0);
+        let secs_per_mb = elapsed.as_secs_f64() / megabytes;
+
+        let is_spike = {
+            let mut state = self.state.lock().unwrap();
+            let baseline = state.baseline_secs_per_mb;
+            state.samples += 1;
+            let is_spike = match baseline {
+                Some(baseline) => {
+                    self.latency_factor > 0.0
+                        && state.samples > WARMUP_SAMPLES
+                        && secs_per_mb > baseline * self.latency_factor
+                }
+                None => false,
+            };
+            if !is_spike {
+                state.baseline_secs_per_mb = Some(match baseline {
+                    Some(baseline) => baseline + LATENCY_SMOOTHING * (secs_per_mb - baseline),
+                    None => secs_per_mb,
+                });
+            }
+            is_spike
+        };
+
+        if is_spike {
+            self.on_overload();
+        } else {
+            self.increase();
+        }
+    }
+
+    /// Halves the limit, at most once per cooldown so that a burst of failures only counts once
+    pub fn on_overload(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(last_decrease) = state.last_decrease {
+            if last_decrease.elapsed() < self.cooldown {
+                return;
+            }
+        }
+        let new_limit = (state.limit / 2).max(self.min_limit);
+        state.pending_shrink += state.limit - new_limit;
+        state.limit = new_limit;
+        state.successes_since_increase = 0;
+        state.last_decrease = Some(Instant::now());
+    }
+
+    fn increase(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.successes_since_increase += 1;
+        if state.successes_since_increase < state.limit || state.limit >= self.max_limit {
+            return;
+        }
+        state.successes_since_increase = 0;
+        state.limit += 1;
+        if state.pending_shrink > 0 {
+            state.pending_shrink -= 1;
+        } else {
+            self.semaphore.add_permits(1);
+        }
+    }
+}
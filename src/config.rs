@@ -1,15 +1,487 @@
+use std::collections::HashMap;
 use std::fs;
 use serde::{Serialize, Deserialize};
 use serde_yaml::from_str;
+use crate::auth::AuthMethod;
+use crate::secrets::SecretSource;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub accepted_users: Vec<String>,
-    pub number_of_threads: i32
+    pub number_of_threads: i32,
+    pub chunked_upload: Option<ChunkedUploadConfig>,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    /// How the hashes in the MediaCMS database were made
+    #[serde(default)]
+    pub hash_mode: HashMode,
+    /// How this program hashes the files it keeps track of itself, in local sidecars and the PeerTube state
+    #[serde(default)]
+    pub hash_scheme: HashScheme,
+    #[serde(default)]
+    pub hash_cache: HashCacheConfig,
+    pub local_duplicates: Option<LocalDuplicateConfig>,
+    pub near_duplicates: Option<NearDuplicateConfig>,
+    pub adaptive_concurrency: Option<AdaptiveConcurrencyConfig>,
+    pub encoding_backlog: Option<EncodingBacklogConfig>,
+    pub encoding_monitor: Option<EncodingMonitorConfig>,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
+    #[serde(default)]
+    pub target: TargetConfig,
+    /// Users that upload somewhere other than `target`
+    #[serde(default)]
+    pub user_targets: HashMap<String, TargetConfig>,
+    #[serde(default)]
+    pub categories: CategoryConfig,
+    pub playlists: Option<PlaylistConfig>,
+    pub subtitles: Option<SubtitleConfig>,
+    pub posters: Option<PosterConfig>,
+}
+
+impl Config {
+    /// Where the files of this user are uploaded to
+    pub fn target_for(&self, username: &str) -> &TargetConfig {
+        self.user_targets.get(username).unwrap_or(&self.target)
+    }
+
+    /// Subtitles and posters are set through forms on the MediaCMS website, which only take a logged
+    /// in session, with ids looked up in the database
+    pub fn check_website_features(&self, mediacms_users: &[String], has_database: bool) -> Result<(), String> {
+        let features: Vec<&str> = [("subtitles", self.subtitles.is_some()), ("posters", self.posters.is_some())]
+            .into_iter()
+            .filter_map(|(name, enabled)| enabled.then_some(name))
+            .collect();
+        if features.is_empty() || mediacms_users.is_empty() {
+            return Ok(());
+        }
+        let features = features.join(" and ");
+        if !has_database {
+            return Err(format!("{} need DATABASE_URL, and do not work with --dry.", features));
+        }
+        let other_users: Vec<&str> = mediacms_users
+            .iter()
+            .filter(|username| self.auth.method_for(username) != AuthMethod::Session)
+            .map(String::as_str)
+            .collect();
+        if !other_users.is_empty() {
+            return Err(format!("{} need the session auth method, which these users do not use: {}", features, other_users.join(", ")));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CategoryConfig {
+    /// Create categories that do not exist in MediaCMS yet, instead of leaving them out
+    pub create_missing: bool,
+    pub rules: Vec<CategoryRule>,
+}
+
+/// Puts files in a category based on the folders below the user folder. A rule applies to a folder
+/// if both the depth and the pattern match, where either can be left out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CategoryRule {
+    /// Position of the folder below the user folder, starting at `1`
+    pub depth: Option<usize>,
+    /// Regular expression the folder name must match
+    pub pattern: Option<String>,
+    /// Name of the category. The folder name is used if not set.
+    pub category: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlaylistConfig {
+    /// Folders at this position below the user folder, starting at `1`, each get a playlist
+    pub depth: usize,
+    /// Which playlist was made for which folder
+    #[serde(default = "default_playlist_state_file")]
+    pub state_file: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SubtitleConfig {
+    /// Language of subtitles named just like the video, e.g. `talk.srt`. They are left out if not set.
+    pub default_language: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PosterConfig {
+    /// Grab a frame with ffmpeg for videos without a poster image. Only images next to the video are used if not set.
+    pub generate: Option<PosterFrameConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PosterFrameConfig {
+    /// Use the first scene change instead of a fixed offset, falling back to the offset if there is none
+    pub scene_detection: bool,
+    /// How different a frame must be from the one before to count as a scene change, from 0 to 1
+    pub scene_threshold: f64,
+    pub offset_secs: f64,
+}
+
+impl Default for PosterFrameConfig {
+    fn default() -> Self {
+        PosterFrameConfig {
+            scene_detection: false,
+            scene_threshold: 0.4,
+            offset_secs: 10.0,
+        }
+    }
+}
+
+/// Where files are uploaded to
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum TargetConfig {
+    #[default]
+    MediaCms,
+    /// Copies files into `path` with a JSON sidecar per file, for rehearsing a run
+    Local { path: String },
+    PeerTube(PeerTubeConfig),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerTubeConfig {
+    /// Base URL of the instance, e.g. `https://videos.example.com`
+    pub url: String,
+    #[serde(default)]
+    pub privacy: PeerTubePrivacy,
+    #[serde(default = "default_chunk_size_mb")]
+    pub chunk_size_mb: u64,
+    /// Unfinished uploads and the files uploaded so far, as PeerTube has no hashes to check against
+    #[serde(default = "default_peertube_state_file")]
+    pub state_file: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerTubePrivacy {
+    #[default]
+    Public,
+    Unlisted,
+    Private,
+    Internal,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BandwidthConfig {
+    /// Total upload bandwidth outside of the scheduled windows. Unlimited if not set.
+    pub limit_mb_per_sec: Option<f64>,
+    pub schedule: Vec<BandwidthRule>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BandwidthRule {
+    /// Days the window applies to, e.g. `mon`. All days if empty.
+    #[serde(default)]
+    pub days: Vec<String>,
+    /// Start and end of the window as `HH:MM` in local time
+    pub from: String,
+    pub to: String,
+    /// Unlimited if neither this nor `paused` is set
+    pub limit_mb_per_sec: Option<f64>,
+    #[serde(default)]
+    pub paused: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HttpConfig {
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    /// Comma separated hosts that bypass the proxies, in the same format as `NO_PROXY`
+    pub no_proxy: Option<String>,
+    pub connect_timeout_secs: u64,
+    /// Upload requests are given up after this long without progress
+    pub read_timeout_secs: Option<u64>,
+    /// Total timeout of an upload request is this plus the time the file takes at `min_throughput_kbps`, and
+    /// the timeout of every other request
+    pub base_timeout_secs: u64,
+    pub min_throughput_kbps: u64,
+    pub tcp_keepalive_secs: Option<u64>,
+    pub pool_max_idle_per_host: Option<usize>,
+    pub pool_idle_timeout_secs: Option<u64>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            http_proxy: None,
+            https_proxy: None,
+            no_proxy: None,
+            connect_timeout_secs: 30,
+            read_timeout_secs: Some(300),
+            base_timeout_secs: 600,
+            min_throughput_kbps: 100,
+            tcp_keepalive_secs: Some(60),
+            pool_max_idle_per_host: None,
+            pool_idle_timeout_secs: Some(90),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    /// PEM file with extra CA certificates to trust, e.g. for a self-hosted CA
+    pub ca_bundle: Option<String>,
+    /// PEM files for mutual TLS
+    pub client_certificate: Option<String>,
+    pub client_key: Option<String>,
+    /// SHA-256 fingerprints of server certificates to trust even without a known CA
+    pub pinned_sha256: Vec<String>,
+    /// Turns off certificate verification completely
+    pub accept_invalid_certificates: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub default_method: AuthMethod,
+    /// Overrides `default_method` for single users
+    pub users: HashMap<String, AuthMethod>,
+    /// Login form used by the `session` method. Defaults to `/accounts/login/` on the MediaCMS server.
+    pub login_url: Option<String>,
+    /// Where passwords and tokens are read from
+    pub secret_source: SecretSource,
+}
+
+impl AuthConfig {
+    pub fn method_for(&self, username: &str) -> AuthMethod {
+        self.users.get(username).copied().unwrap_or(self.default_method)
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            default_method: AuthMethod::Basic,
+            users: HashMap::new(),
+            login_url: None,
+            secret_source: SecretSource::Env,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkedUploadConfig {
+    /// The fine-uploader endpoint of MediaCMS, usually `https://[host]/fu/upload/`
+    pub url: String,
+    #[serde(default = "default_chunk_size_mb")]
+    pub chunk_size_mb: u64,
+    #[serde(default = "default_progress_file")]
+    pub progress_file: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// Total number of upload attempts per file, including the first one
+    pub max_attempts: u32,
+    pub base_delay_secs: u64,
+    pub max_delay_secs: u64,
+    pub queue_file: String,
+    /// Steps after the upload, like adding to a playlist, that failed and are tried again
+    pub follow_up_file: String,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 5,
+            base_delay_secs: 10,
+            max_delay_secs: 600,
+            queue_file: String::from("retry_queue.json"),
+            follow_up_file: String::from("follow_ups.json"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashMode {
+    /// The first 128 KB and the file size, as computed by the patched `models.py`
+    #[default]
+    Partial,
+    /// MD5 of the whole file, as computed by stock MediaCMS
+    Full,
+    /// Found by comparing a sample of local files with the database
+    Auto,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashScheme {
+    #[default]
+    PartialMd5,
+    Md5,
+    Sha256,
+    Blake3,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HashCacheConfig {
+    pub enabled: bool,
+    pub file: String,
+}
+
+impl Default for HashCacheConfig {
+    fn default() -> Self {
+        HashCacheConfig {
+            enabled: true,
+            file: String::from("hash_cache.jsonl"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicatePolicy {
+    /// The copy whose path sorts first is uploaded
+    #[default]
+    FirstPath,
+    /// The copy of the first user in `preferred_users` that has one is uploaded
+    PreferredUser,
+    /// Every copy is uploaded, the duplicates are only reported
+    UploadAll,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LocalDuplicateConfig {
+    pub policy: DuplicatePolicy,
+    pub preferred_users: Vec<String>,
+    /// Compare the whole files of copies whose partial hashes match
+    pub full_hash: bool,
+    /// Give the uploaded copy the tags of the copies that are left out
+    pub merge_tags: bool,
+    pub report_file: String,
+}
+
+impl Default for LocalDuplicateConfig {
+    fn default() -> Self {
+        LocalDuplicateConfig {
+            policy: DuplicatePolicy::FirstPath,
+            preferred_users: vec![],
+            full_hash: false,
+            merge_tags: false,
+            report_file: String::from("local_duplicates.json"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NearDuplicateConfig {
+    /// Frames compared per video
+    pub frames: usize,
+    /// Bits two frames may differ by and still count as the same picture, out of 64
+    pub max_distance: u32,
+    /// Leave out likely duplicates instead of only reporting them
+    pub skip: bool,
+    /// Also fingerprint files that are already in the target, so copies of older media are found
+    pub index_existing: bool,
+    pub index_file: String,
+    pub report_file: String,
+}
+
+impl Default for NearDuplicateConfig {
+    fn default() -> Self {
+        NearDuplicateConfig {
+            frames: 6,
+            max_distance: 10,
+            skip: false,
+            index_existing: false,
+            index_file: String::from("fingerprints.json"),
+            report_file: String::from("near_duplicates.json"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AdaptiveConcurrencyConfig {
+    pub min_threads: usize,
+    /// Defaults to `number_of_threads`
+    pub max_threads: Option<usize>,
+    /// An upload this many times slower per MB than usual counts as overload. `0` turns it off.
+    pub latency_factor: f64,
+    /// Minimum time between two decreases of the limit
+    pub cooldown_secs: u64,
+}
+
+impl Default for AdaptiveConcurrencyConfig {
+    fn default() -> Self {
+        AdaptiveConcurrencyConfig {
+            min_threads: 1,
+            max_threads: None,
+            latency_factor: 3.0,
+            cooldown_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncodingBacklogConfig {
+    /// New uploads pause while more encodes than this are pending or running
+    pub max_backlog: i64,
+    /// Uploads resume once the backlog is down to this. Defaults to half of `max_backlog`.
+    pub resume_below: Option<i64>,
+    #[serde(default = "default_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+}
+
+fn default_poll_interval_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncodingMonitorConfig {
+    #[serde(default = "default_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+    /// Stop following media whose encoding takes longer than this, counting the upload as a success
+    #[serde(default = "default_max_encoding_wait_secs")]
+    pub max_wait_secs: u64,
+    /// How many times media whose encoding failed is deleted and uploaded again. Off if `0`.
+    #[serde(default)]
+    pub reupload_attempts: u32,
+}
+
+fn default_max_encoding_wait_secs() -> u64 {
+    6 * 60 * 60
+}
+
+fn default_chunk_size_mb() -> u64 {
+    10
+}
+
+fn default_progress_file() -> String {
+    String::from("upload_progress.json")
+}
+
+fn default_playlist_state_file() -> String {
+    String::from("playlists.json")
+}
+
+fn default_peertube_state_file() -> String {
+    String::from("peertube_state.json")
 }
 
 pub fn read_config(path: &str) -> serde_yaml::Result<Config> {
     let contents = fs::read_to_string(path)
         .expect("Something went wrong reading the file");
     from_str::<Config>(&contents)
-}
\ No newline at end of file
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 use colored::Colorize;
-use sqlx::{Error, Pool, Postgres};
+use sqlx::{Error, Pool, Postgres, Transaction};
 use sqlx::postgres::PgPoolOptions;
 
 pub async fn create_database_pool(database_url: &str) -> Result<Pool<Postgres>, Error> {
@@ -39,5 +39,167 @@ pub async fn get_file_details_from_db(pool: Pool<Postgres>) -> Result<HashMap<u6
     }
 }
 
+/// Number of encodings that are waiting and running in MediaCMS
+pub async fn get_encoding_backlog(pool: &Pool<Postgres>) -> Result<(i64, i64), Error> {
+    let (pending, running) = sqlx::query_as::<_, (i64, i64)>(
+        "SELECT COUNT(*) FILTER (WHERE status = 'pending'), COUNT(*) FILTER (WHERE status = 'running') FROM files_encoding"
+    )
+        .fetch_one(pool).await?;
+    Ok((pending, running))
+}
+
+/// Adds tags to the media with this friendly token. The MediaCMS API can not set tags, so they are
+/// written to the tag tables directly, normalised the same way MediaCMS does when it saves a tag.
+pub async fn set_media_tags(pool: &Pool<Postgres>, friendly_token: &str, tags: &[String]) -> Result<(), Error> {
+    let mut titles: Vec<String> = Vec::new();
+    for tag in tags {
+        let title = normalize_tag(tag);
+        if !title.is_empty() && !titles.contains(&title) {
+            titles.push(title);
+        }
+    }
+    if titles.is_empty() {
+        return Ok(());
+    }
+
+    let mut transaction = pool.begin().await?;
+    ensure_media_exists(&mut transaction, friendly_token).await?;
+
+    sqlx::query(
+        "INSERT INTO files_tag (title, user_id, media_count) \
+         SELECT title, (SELECT user_id FROM files_media WHERE friendly_token = $2), 0 FROM UNNEST($1::text[]) AS title \
+         ON CONFLICT (title) DO NOTHING"
+    )
+        .bind(&titles)
+        .bind(friendly_token)
+        .execute(&mut *transaction).await?;
+
+    sqlx::query(
+        "INSERT INTO files_media_tags (media_id, tag_id) \
+         SELECT files_media.id, files_tag.id FROM files_media, files_tag \
+         WHERE files_media.friendly_token = $1 AND files_tag.title = ANY($2) \
+         ON CONFLICT (media_id, tag_id) DO NOTHING"
+    )
+        .bind(friendly_token)
+        .bind(&titles)
+        .execute(&mut *transaction).await?;
+
+    // Same count as Tag.update_tag_media in MediaCMS
+    sqlx::query(
+        "UPDATE files_tag SET media_count = ( \
+             SELECT COUNT(*) FROM files_media_tags JOIN files_media ON files_media.id = files_media_tags.media_id \
+             WHERE files_media_tags.tag_id = files_tag.id AND files_media.state = 'public' AND files_media.is_reviewed \
+         ) WHERE title = ANY($1)"
+    )
+        .bind(&titles)
+        .execute(&mut *transaction).await?;
+
+    transaction.commit().await
+}
+
+/// Adds the media with this friendly token to the categories, which can not be done through the
+/// MediaCMS API either. Categories that do not exist are left out, unless `create_missing` is set.
+pub async fn set_media_categories(
+    pool: &Pool<Postgres>,
+    friendly_token: &str,
+    categories: &[String],
+    create_missing: bool,
+) -> Result<(), Error> {
+    if categories.is_empty() {
+        return Ok(());
+    }
+    // Longer titles do not fit in the category table
+    let titles: Vec<String> = categories.iter().map(|category| category.chars().take(100).collect()).collect();
+
+    let mut transaction = pool.begin().await?;
+    ensure_media_exists(&mut transaction, friendly_token).await?;
+
+    if create_missing {
+        // Categories made here belong to no user, so they are global. md5 gives a random UUID without
+        // needing the pgcrypto extension.
+        sqlx::query(
+            "INSERT INTO files_category (uid, add_date, title, description, is_global, media_count, thumbnail) \
+             SELECT md5(random()::text || clock_timestamp()::text)::uuid, now(), title, '', true, 0, '' \
+             FROM UNNEST($1::text[]) AS title \
+             ON CONFLICT (title) DO NOTHING"
+        )
+            .bind(&titles)
+            .execute(&mut *transaction).await?;
+    }
+
+    sqlx::query(
+        "INSERT INTO files_media_category (media_id, category_id) \
+         SELECT files_media.id, files_category.id FROM files_media, files_category \
+         WHERE files_media.friendly_token = $1 AND files_category.title = ANY($2) \
+         ON CONFLICT (media_id, category_id) DO NOTHING"
+    )
+        .bind(friendly_token)
+        .bind(&titles)
+        .execute(&mut *transaction).await?;
 
+    // Same count as Category.update_category_media in MediaCMS
+    sqlx::query(
+        "UPDATE files_category SET media_count = ( \
+             SELECT COUNT(*) FROM files_media_category JOIN files_media ON files_media.id = files_media_category.media_id \
+             WHERE files_media_category.category_id = files_category.id AND files_media.listable \
+         ) WHERE title = ANY($1)"
+    )
+        .bind(&titles)
+        .execute(&mut *transaction).await?;
 
+    transaction.commit().await
+}
+
+async fn ensure_media_exists(transaction: &mut Transaction<'_, Postgres>, friendly_token: &str) -> Result<(), Error> {
+    let (media_exists,) = sqlx::query_as::<_, (bool,)>("SELECT EXISTS (SELECT 1 FROM files_media WHERE friendly_token = $1)")
+        .bind(friendly_token)
+        .fetch_one(&mut **transaction).await?;
+    if media_exists {
+        Ok(())
+    } else {
+        Err(Error::RowNotFound)
+    }
+}
+
+/// Ids of the categories with these titles
+pub async fn get_category_ids(pool: &Pool<Postgres>, titles: &[String]) -> Result<Vec<i64>, Error> {
+    let rows = sqlx::query_as::<_, (i64,)>("SELECT id::bigint FROM files_category WHERE title = ANY($1)")
+        .bind(titles)
+        .fetch_all(pool).await?;
+    Ok(rows.into_iter().map(|(id,)| id).collect())
+}
+
+/// Id of the MediaCMS language with this code, compared without case
+pub async fn get_language_id(pool: &Pool<Postgres>, code: &str) -> Result<Option<i64>, Error> {
+    let row = sqlx::query_as::<_, (i64,)>("SELECT id::bigint FROM files_language WHERE lower(code) = lower($1) LIMIT 1")
+        .bind(code)
+        .fetch_optional(pool).await?;
+    Ok(row.map(|(id,)| id))
+}
+
+/// Tags in MediaCMS only keep alphanumeric characters, in lowercase, and at most 99 of them
+fn normalize_tag(tag: &str) -> String {
+    tag.chars()
+        .filter(|character| character.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .take(99)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags_keep_only_lowercase_alphanumerics() {
+        assert_eq!(normalize_tag("Summer Trip 2023!"), "summertrip2023");
+        assert_eq!(normalize_tag("Ärger-Über"), "ärgerüber");
+        assert_eq!(normalize_tag("--"), "");
+    }
+
+    #[test]
+    fn tags_are_cut_at_99_characters() {
+        assert_eq!(normalize_tag(&"a".repeat(150)), "a".repeat(99));
+        assert_eq!(normalize_tag(&"a b".repeat(60)).chars().count(), 99);
+    }
+}
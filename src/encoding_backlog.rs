@@ -0,0 +1,61 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use sqlx::{Pool, Postgres};
+use tokio::sync::watch;
+use crate::config::EncodingBacklogConfig;
+use crate::db;
+use crate::shared_state::SharedState;
+
+/// Tells upload tasks whether MediaCMS has room for more encodes. Without a monitor the gate is
+/// always open.
+#[derive(Clone)]
+pub struct EncodingGate {
+    receiver: Option<watch::Receiver<bool>>,
+}
+
+impl EncodingGate {
+    pub fn always_open() -> EncodingGate {
+        EncodingGate { receiver: None }
+    }
+
+    pub async fn wait_until_open(&self) {
+        if let Some(receiver) = &self.receiver {
+            let mut receiver = receiver.clone();
+            // An error means the monitor stopped, in which case uploads just continue
+            let _ = receiver.wait_for(|open| *open).await;
+        }
+    }
+}
+
+/// Polls the encoding tables in the background and closes the gate while the backlog is above
+/// `max_backlog`, opening it again once it has drained to `resume_below`
+pub fn spawn_backlog_monitor(
+    pool: Pool<Postgres>,
+    config: &EncodingBacklogConfig,
+    shared_state: Arc<Mutex<SharedState>>,
+) -> EncodingGate {
+    let (sender, receiver) = watch::channel(true);
+    let max_backlog = config.max_backlog;
+    let resume_below = config.resume_below.unwrap_or(max_backlog / 2).min(max_backlog);
+    let interval = Duration::from_secs(config.poll_interval_secs.max(1));
+
+    tokio::spawn(async move {
+        let mut paused = false;
+        loop {
+            // On a failed query the last known state is kept
+            if let Ok((pending, running)) = db::get_encoding_backlog(&pool).await {
+                let backlog = pending + running;
+                if paused && backlog <= resume_below {
+                    paused = false;
+                } else if !paused && backlog > max_backlog {
+                    paused = true;
+                }
+                let _ = sender.send(!paused);
+                shared_state.lock().unwrap().set_encoding_backlog(pending, running, paused);
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+
+    EncodingGate { receiver: Some(receiver) }
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::config::EncodingMonitorConfig;
+use crate::path_data::PathData;
+use crate::upload_target::{EncodingStatus, UploadedMedia, UploadTarget};
+
+/// Follows uploaded media until the server has encoded it, and keeps track of how often each file
+/// has been uploaded again because its encoding failed
+pub struct EncodingMonitor {
+    poll_interval: Duration,
+    max_wait: Duration,
+    reupload_attempts: u32,
+    reuploads: Mutex<HashMap<String, u32>>,
+    /// Media being followed right now, so the run does not end before they are done
+    following: AtomicUsize,
+}
+
+impl EncodingMonitor {
+    pub fn new(config: &EncodingMonitorConfig) -> EncodingMonitor {
+        EncodingMonitor {
+            poll_interval: Duration::from_secs(config.poll_interval_secs.max(1)),
+            max_wait: Duration::from_secs(config.max_wait_secs),
+            reupload_attempts: config.reupload_attempts,
+            reuploads: Mutex::new(HashMap::new()),
+            following: AtomicUsize::new(0),
+        }
+    }
+
+    /// Must be called before the follow task is spawned, and paired with `stop_following`
+    pub fn start_following(&self) {
+        self.following.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn stop_following(&self) {
+        self.following.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn is_following(&self) -> bool {
+        self.following.load(Ordering::SeqCst) > 0
+    }
+
+    /// Polls the target until encoding has succeeded or failed. Returns `Pending` if it took longer
+    /// than the maximum wait. Errors while polling are ignored, as the next poll may get through.
+    pub async fn wait_for_encoding(&self, target: &dyn UploadTarget, data: &PathData, media: &UploadedMedia) -> EncodingStatus {
+        let deadline = Instant::now() + self.max_wait;
+        loop {
+            tokio::time::sleep(self.poll_interval).await;
+            match target.encoding_status(data, media).await {
+                Ok(EncodingStatus::Pending) | Err(_) => {}
+                Ok(status) => return status,
+            }
+            if Instant::now() >= deadline {
+                return EncodingStatus::Pending;
+            }
+        }
+    }
+
+    /// Whether the file may be uploaded again, counting the attempt if so
+    pub fn should_reupload(&self, path: &str) -> bool {
+        let mut reuploads = self.reuploads.lock().unwrap();
+        let attempts = reuploads.entry(path.to_string()).or_insert(0);
+        if *attempts < self.reupload_attempts {
+            *attempts += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -1,7 +1,20 @@
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::Read;
 use std::path::Path;
-use crate::file_extension::FileExtension::{Avi, Mov, Mp4, Mpeg, Ogv, Unknown, Webm, Wmv};
+use crate::file_extension::FileExtension::{Avi, Mkv, Mov, Mp4, Mpeg, Ogv, Unknown, Webm, Wmv};
 
-#[derive(Debug, PartialEq)]
+/// Number of bytes read from the start of a file to detect its container
+const SNIFF_LENGTH: usize = 64;
+const EBML_MAGIC: [u8; 4] = [0x1A, 0x45, 0xDF, 0xA3];
+const ASF_MAGIC: [u8; 16] = [
+    0x30, 0x26, 0xB2, 0x75, 0x8E, 0x66, 0xCF, 0x11, 0xA6, 0xD9, 0x00, 0xAA, 0x00, 0x62, 0xCE, 0x6C,
+];
+const MPEG_PACK_HEADER: [u8; 4] = [0x00, 0x00, 0x01, 0xBA];
+const MPEG_SEQUENCE_HEADER: [u8; 4] = [0x00, 0x00, 0x01, 0xB3];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum FileExtension {
     Mp4,
     Avi,
@@ -10,6 +23,8 @@ pub enum FileExtension {
     Webm,
     Mov,
     Wmv,
+    /// Only found by content, as Matroska files are not picked up by extension
+    Mkv,
     Unknown,
 }
 
@@ -47,7 +62,101 @@ impl FileExtension {
             Webm => "video/webm",
             Mov => "video/quicktime",
             Wmv => "video/x-ms-wmv",
+            Mkv => "video/x-matroska",
             Unknown => ""
         }
     }
+
+    /// Detects the container from the magic bytes at the start of the file
+    pub fn sniff(path: &Path) -> io::Result<FileExtension> {
+        let mut header = Vec::with_capacity(SNIFF_LENGTH);
+        File::open(path)?.take(SNIFF_LENGTH as u64).read_to_end(&mut header)?;
+        Ok(FileExtension::from_header(&header))
+    }
+
+    fn from_header(header: &[u8]) -> FileExtension {
+        if header.len() >= 12 && &header[4..8] == b"ftyp" {
+            // The major brand tells QuickTime apart from the other ISO base media formats
+            if &header[8..12] == b"qt  " {
+                Mov
+            } else {
+                Mp4
+            }
+        } else if header.len() >= 8 && matches!(&header[4..8], b"moov" | b"mdat" | b"wide" | b"free" | b"skip") {
+            // Old QuickTime files start without an ftyp box
+            Mov
+        } else if header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"AVI " {
+            Avi
+        } else if header.starts_with(&EBML_MAGIC) {
+            if header.windows(4).any(|window| window == b"webm") {
+                Webm
+            } else {
+                Mkv
+            }
+        } else if header.starts_with(&ASF_MAGIC) {
+            Wmv
+        } else if header.starts_with(&MPEG_PACK_HEADER) || header.starts_with(&MPEG_SEQUENCE_HEADER) {
+            Mpeg
+        } else if header.starts_with(b"OggS") {
+            Ogv
+        } else {
+            Unknown
+        }
+    }
+}
+
+impl Display for FileExtension {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Mp4 => "MP4",
+            Avi => "AVI",
+            Mpeg => "MPEG",
+            Ogv => "Ogg",
+            Webm => "WebM",
+            Mov => "QuickTime",
+            Wmv => "WMV",
+            Mkv => "Matroska",
+            Unknown => "unknown",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iso_media_is_told_apart_by_major_brand() {
+        assert_eq!(FileExtension::from_header(b"\x00\x00\x00\x18ftypisom\x00\x00\x02\x00"), Mp4);
+        assert_eq!(FileExtension::from_header(b"\x00\x00\x00\x14ftypqt  \x00\x00\x02\x00"), Mov);
+        assert_eq!(FileExtension::from_header(b"\x00\x00\x00\x08wide\x00\x00\x00\x00"), Mov);
+    }
+
+    #[test]
+    fn matroska_is_told_apart_by_doc_type() {
+        let mut webm = EBML_MAGIC.to_vec();
+        webm.extend_from_slice(b"\x42\x82\x84webm");
+        assert_eq!(FileExtension::from_header(&webm), Webm);
+        let mut mkv = EBML_MAGIC.to_vec();
+        mkv.extend_from_slice(b"\x42\x82\x88matroska");
+        assert_eq!(FileExtension::from_header(&mkv), Mkv);
+    }
+
+    #[test]
+    fn other_containers_are_detected() {
+        assert_eq!(FileExtension::from_header(b"RIFF\x00\x00\x00\x00AVI LIST"), Avi);
+        assert_eq!(FileExtension::from_header(&ASF_MAGIC), Wmv);
+        assert_eq!(FileExtension::from_header(&MPEG_PACK_HEADER), Mpeg);
+        assert_eq!(FileExtension::from_header(&MPEG_SEQUENCE_HEADER), Mpeg);
+        assert_eq!(FileExtension::from_header(b"OggS\x00\x02"), Ogv);
+    }
+
+    #[test]
+    fn short_or_unknown_headers_are_unknown() {
+        assert_eq!(FileExtension::from_header(b""), Unknown);
+        assert_eq!(FileExtension::from_header(b"\x00\x00\x00\x18ftyp"), Unknown);
+        assert_eq!(FileExtension::from_header(b"RIFF\x00\x00\x00\x00WAVE"), Unknown);
+        assert_eq!(FileExtension::from_header(b"plain text"), Unknown);
+    }
 }
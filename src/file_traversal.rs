@@ -1,121 +1,365 @@
+use std::fs;
 use std::fs::{read_dir};
 use std::{io};
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use reqwest::{Client};
-use tokio::sync::{Semaphore};
+use tokio::sync::oneshot;
 use tokio::task;
+use crate::categories::CategoryRules;
 use crate::config::Config;
 use crate::{file_utils, SharedState};
 use crate::file_extension::FileExtension;
-use crate::file_utils::{compute_hash_of_partial_file, get_file_buffer, get_file_size, upload_file};
+use crate::local_duplicates::{find_duplicates, DuplicatePlan};
+use crate::file_utils::{get_file_size, run_follow_ups, upload_file};
+use crate::hash_strategy::StoredHash;
 use crate::path_data::PathData;
+use crate::upload_context::UploadContext;
 use crate::upload_status::UploadStatus;
 
+/// User that uploads files which are not inside a folder of an accepted user
+pub const DEFAULT_UPLOADER: &str = "Default_Uploader";
+
 pub(crate) async fn iterate_over_files_and_upload(
     path: &str,
-    file_metadata_from_db: HashMap<u64, Vec<String>>,
-    client: Arc<Client>,
+    context: UploadContext,
     config: Config,
     shared_state: &Arc<Mutex<SharedState>>,
-    new_file_paths: Vec<PathBuf>
+    files: Vec<(PathBuf, u64)>,
+    new_file_paths: Vec<PathBuf>,
 ) {
     let root = path;
-    let original_paths = get_files_in_directory(path).unwrap_or_else(|_| vec![]);
+    let file_sizes: HashMap<String, u64> = files
+        .iter()
+        .filter_map(|(path, size)| Some((path.to_str()?.to_string(), *size)))
+        .collect();
+    let original_paths: Vec<PathBuf> = files.into_iter().map(|(path, _)| path).collect();
+    let retry_queue = &context.retry_queue;
+    // An unreachable root would otherwise empty the whole cache
+    if !original_paths.is_empty() {
+        context.hash_cache.retain(&original_paths);
+        if let Some(detector) = &context.near_duplicates {
+            detector.retain(&original_paths);
+        }
+    }
+
+    // Files that were still waiting for a retry when the last run ended go first
+    let leftover_paths: Vec<PathBuf> = retry_queue
+        .leftover_paths()
+        .into_iter()
+        .filter_map(|leftover| {
+            let leftover_path = PathBuf::from(&leftover);
+            if original_paths.contains(&leftover_path) {
+                Some(leftover_path)
+            } else {
+                retry_queue.remove(&leftover);
+                None
+            }
+        })
+        .collect();
+
     let mut paths: Vec<PathBuf> = original_paths
         .iter()
-        .filter(|x| !new_file_paths.contains(x))
+        .filter(|x| !new_file_paths.contains(x) && !leftover_paths.contains(x))
         .cloned()
         .collect();
 
-    for file in new_file_paths {
+    for file in new_file_paths.into_iter().filter(|x| !leftover_paths.contains(x)) {
+        paths.insert(0, file)
+    }
+
+    for file in leftover_paths {
         paths.insert(0, file)
     }
 
     let total_paths = paths.len();
 
-    let file_metadata_from_db = Arc::new(file_metadata_from_db);
+    let context = Arc::new(context);
+
+    // Copies of the same file in different folders are not in the target yet, so they are found up
+    // front. Finding them reads the files, so it runs on a blocking thread.
+    let duplicates = Arc::new(match config.local_duplicates.clone() {
+        Some(duplicate_config) => {
+            let context = context.clone();
+            let paths = paths.clone();
+            let file_sizes = file_sizes.clone();
+            let root = root.to_string();
+            let acceptable_users = config.accepted_users.clone();
+            task::spawn_blocking(move || {
+                find_duplicates(
+                    &duplicate_config,
+                    &paths,
+                    &file_sizes,
+                    &context.hash_cache,
+                    &root,
+                    &acceptable_users,
+                    &context.category_rules,
+                )
+            })
+            .await
+            .unwrap_or_default()
+        }
+        None => DuplicatePlan::default(),
+    });
+    // Files with the size of existing media are likely skipped, so they only count once they turn
+    // out to need an upload
+    let likely_uploads: HashMap<String, u64> = file_sizes
+        .iter()
+        .filter(|(path, size)| {
+            duplicates.kept_copy_of(path).is_none()
+                && context.target
+                    .hash_strategies(&username_for(path, root, &config.accepted_users), **size)
+                    .is_empty()
+        })
+        .map(|(path, size)| (path.clone(), *size))
+        .collect();
+    shared_state.lock().unwrap().set_remaining_file_sizes(likely_uploads);
 
-    let concurrency_limit: usize = config.number_of_threads as usize;
-    let semaphore = Arc::new(Semaphore::new(concurrency_limit));
+    shared_state.lock().unwrap().set_upload_limit(context.limiter.limit(), context.limiter.max_limit());
 
     let mut tasks = Vec::new();
 
     shared_state.lock().unwrap().set_initial_remaining_files((total_paths) as i32);
 
+    let files = Arc::new(FileContext {
+        root: root.to_string(),
+        acceptable_users: config.accepted_users.clone(),
+        duplicates: duplicates.clone(),
+        shared_state: shared_state.clone(),
+        context: context.clone(),
+    });
+
+    // Copies left out for another copy wait for it, and the next copy is uploaded if it does not make it
+    let mut fallback_tasks = Vec::new();
+    for (kept, copies) in duplicates.groups() {
+        let outcome = shared_state.lock().unwrap().watch_outcome(kept);
+        let copies: Vec<(String, u64)> = copies
+            .iter()
+            .filter_map(|copy| Some((copy.clone(), *file_sizes.get(copy)?)))
+            .collect();
+        for (copy, _) in &copies {
+            context.retry_queue.remove(copy);
+        }
+        fallback_tasks.push(task::spawn(upload_copies_if_needed(outcome, copies, files.clone())));
+    }
+
     for path in paths.into_iter() {
-        let acceptable_users = config.accepted_users.clone();
-        let client = client.clone();
-        let file_metadata_from_db = file_metadata_from_db.clone();
-        let root = root.to_string();
-        let semaphore = semaphore.clone();
-        let shared_clone = shared_state.clone();
-
-        let task = task::spawn(async move {
-            let _permit = semaphore.acquire().await.unwrap();
-
-            let file_size = match get_file_size(&path) {
-                Ok(size) => size,
-                Err(error) => {
-                    println!("Could not get size of file {:?}. Reason: {}", path, error);
-                    panic!()
-                }
-            };
+        let path_str = path.to_string_lossy().to_string();
+        if duplicates.kept_copy_of(&path_str).is_some() {
+            continue;
+        }
+        let Some(file_size) = file_sizes.get(&path_str).copied() else {
+            println!("Could not get string slice from path {:?}", path);
+            shared_state.lock().unwrap().append_to_processed_files((UploadStatus::Failed(0), path_str));
+            continue;
+        };
 
-            let path_str = match path.to_str() {
-                Some(path_str) => path_str,
-                None => {
-                    println!("Could not get string slice from path {:?}", path);
-                    panic!()
-                }
-            };
+        tasks.push(task::spawn(check_and_upload(path_str, file_size, files.clone())));
+    }
+
+    for task in tasks {
+        let _ = task.await; // Handle or ignore the result/error here
+    }
+    // Only copies uploaded in place of another one are hashed after this, so nearly all hashes are in
+    context.hash_cache.flush();
+    if let Some(detector) = &context.near_duplicates {
+        detector.flush();
+    }
+
+    // Keep going until every transient failure has either been uploaded or run out of attempts
+    loop {
+        let pending = context.retry_queue.take_pending();
+        let pending_follow_ups = context.follow_ups.take_pending();
+        if pending.is_empty() && pending_follow_ups.is_empty() {
+            // Files uploaded again after a failed encode, and copies uploaded in place of a file that
+            // did not make it, can still end up in the retry queue
+            let following = context.encoding_monitor.as_ref().is_some_and(|monitor| monitor.is_following());
+            if following || fallback_tasks.iter().any(|task| !task.is_finished()) {
+                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                continue;
+            }
+            break;
+        }
 
-            let path_slice: &Path = path.as_path();
+        let mut retry_tasks = Vec::new();
 
-            if !file_metadata_from_db.contains_key(&file_size) {
-                match file_utils::check_file_integrity(&path) {
-                    true => {
-                        shared_clone.lock().unwrap().append_to_currently_uploading(path.to_str().unwrap().to_string());
-                        let data = read_file(path_str, &root, &acceptable_users);
-                        upload_file(data, &client, path_str, shared_clone).await;
+        for (path, due) in pending {
+            let acceptable_users = config.accepted_users.clone();
+            let context = context.clone();
+            let duplicates = duplicates.clone();
+            let root = root.to_string();
+            let shared_clone = shared_state.clone();
+
+            let task = task::spawn(async move {
+                tokio::time::sleep_until(due).await;
+                let _permit = context.limiter.acquire().await;
+
+                match get_file_size(Path::new(&path)) {
+                    Ok(file_size) => {
+                        let mut data = read_file(&path, &root, &acceptable_users, &context.category_rules, file_size);
+                        if let Ok(data) = &mut data {
+                            duplicates.merge_tags(data);
+                        }
+                        upload_file(data, &path, shared_clone, &context).await
                     }
-                    false => {
-                        shared_clone.lock().unwrap().append_to_processed_files((UploadStatus::Corrupt, path.to_str().unwrap().to_string()));
+                    Err(_) => {
+                        // The file disappeared while waiting
+                        context.retry_queue.remove(&path);
+                        shared_clone.lock().unwrap().append_to_processed_files((UploadStatus::Failed(0), path));
                     }
                 }
-            } else {
-                let partial_hash = match compute_hash_of_partial_file(path_slice) {
-                    Ok(hash) => hash,
-                    Err(error) => {
-                        println!("Could not get partial hash of file, {:?}. Reason: {}", path_slice, error);
-                        panic!()
-                    }
-                };
+            });
+            retry_tasks.push(task);
+        }
 
-                if !file_metadata_from_db.get(&file_size).unwrap().contains(&partial_hash) {
-                    match file_utils::check_file_integrity(&path) {
-                        true => {
-                            let data = read_file(path_str, &root, &acceptable_users);
-                            upload_file(data, &client, path_str, shared_clone).await
-                        }
-                        false => {
-                            shared_clone.lock().unwrap().append_to_processed_files((UploadStatus::Corrupt, path.to_str().unwrap().to_string()));
-                        }
+        for (media, entry, due) in pending_follow_ups {
+            let acceptable_users = config.accepted_users.clone();
+            let context = context.clone();
+            let duplicates = duplicates.clone();
+            let root = root.to_string();
+            let shared_clone = shared_state.clone();
+
+            let task = task::spawn(async move {
+                tokio::time::sleep_until(due).await;
+
+                let data = get_file_size(Path::new(&entry.path))
+                    .ok()
+                    .and_then(|file_size| read_file(&entry.path, &root, &acceptable_users, &context.category_rules, file_size).ok());
+                match data {
+                    Some(mut data) => {
+                        duplicates.merge_tags(&mut data);
+                        run_follow_ups(&data, &media, &entry.steps, &entry.path, &shared_clone, &context).await
                     }
-                } else {
-                    shared_clone
-                        .lock()
-                        .unwrap()
-                        .append_to_processed_files((UploadStatus::Skipped, path.to_str().unwrap().to_string()));
+                    // The file is gone, so there is nothing left to take the steps from
+                    None => context.follow_ups.remove(&media),
                 }
+            });
+            retry_tasks.push(task);
+        }
+
+        for task in retry_tasks {
+            let _ = task.await;
+        }
+    }
+}
+
+/// What checking and uploading a single file needs
+struct FileContext {
+    root: String,
+    acceptable_users: Vec<String>,
+    duplicates: Arc<DuplicatePlan>,
+    shared_state: Arc<Mutex<SharedState>>,
+    context: Arc<UploadContext>,
+}
+
+/// Uploads the file unless the target already has it
+async fn check_and_upload(path_str: String, file_size: u64, files: Arc<FileContext>) {
+    let context = &files.context;
+    let shared_state = files.shared_state.clone();
+    let _permit = context.limiter.acquire().await;
+    let path = Path::new(&path_str);
+
+    // The user decides which target the file is checked against
+    let mut data = read_file(&path_str, &files.root, &files.acceptable_users, &context.category_rules, file_size);
+    if let Ok(data) = &mut data {
+        files.duplicates.merge_tags(data);
+    }
+    let username = data
+        .as_ref()
+        .map(|data| data.username.clone())
+        .unwrap_or_else(|_| DEFAULT_UPLOADER.to_string());
+
+    // Hashed in every way the existing media of this size was, so older hashes still match
+    let mut exists = false;
+    for strategy in context.target.hash_strategies(&username, file_size) {
+        let hash = match context.hash_cache.get_or_compute_async(path, strategy).await {
+            Ok(hash) => StoredHash::new(strategy, hash),
+            Err(error) => {
+                println!("Could not get {} hash of file, {:?}. Reason: {}", strategy.id(), path, error);
+                context.retry_queue.remove(&path_str);
+                shared_state.lock().unwrap().append_to_processed_files((UploadStatus::Failed(0), path_str));
+                return;
             }
-        });
-        tasks.push(task);
+        };
+        if context.target.contains(&username, file_size, &hash) {
+            exists = true;
+            break;
+        }
     }
 
-    for task in tasks {
-        let _ = task.await; // Handle or ignore the result/error here
+    if !exists {
+        shared_state.lock().unwrap().add_remaining_file_size(path_str.clone(), file_size);
+        upload_new_file(data, &path_str, file_size, shared_state, context).await;
+    } else {
+        if let Some(detector) = &context.near_duplicates {
+            detector.add_existing(&path_str, file_size).await;
+        }
+        context.retry_queue.remove(&path_str);
+        shared_state.lock().unwrap().append_to_processed_files((UploadStatus::Skipped, path_str));
+    }
+}
+
+/// Waits until the copy that was kept is done, then leaves the other copies out if the target has
+/// it, or uploads the next copy in its place if it failed or was left out itself
+async fn upload_copies_if_needed(
+    mut outcome: oneshot::Receiver<UploadStatus>,
+    copies: Vec<(String, u64)>,
+    files: Arc<FileContext>,
+) {
+    let mut copies = copies.into_iter();
+    loop {
+        let in_target = matches!(
+            outcome.await,
+            Ok(UploadStatus::Success | UploadStatus::Skipped | UploadStatus::EncodeFailed)
+        );
+        if in_target {
+            let mut state = files.shared_state.lock().unwrap();
+            for (copy, _) in copies {
+                state.append_to_processed_files((UploadStatus::Duplicate, copy));
+            }
+            return;
+        }
+        let Some((next, file_size)) = copies.next() else {
+            return;
+        };
+        outcome = files.shared_state.lock().unwrap().watch_outcome(&next);
+        check_and_upload(next, file_size, files.clone()).await;
+    }
+}
+
+/// Uploads a file that is not in the target yet, unless it is corrupt or a likely copy of uploaded media
+async fn upload_new_file(
+    data: Result<PathData, std::fmt::Error>,
+    path_str: &str,
+    file_size: u64,
+    shared_state: Arc<Mutex<SharedState>>,
+    context: &Arc<UploadContext>,
+) {
+    if !file_utils::check_file_integrity(&PathBuf::from(path_str)) {
+        context.retry_queue.remove(path_str);
+        shared_state.lock().unwrap().append_to_processed_files((UploadStatus::Corrupt, path_str.to_string()));
+        return;
+    }
+    if let Some(detector) = &context.near_duplicates {
+        if detector.check(path_str, file_size).await.is_some() && detector.skips_matches() {
+            context.retry_queue.remove(path_str);
+            shared_state.lock().unwrap().append_to_processed_files((UploadStatus::LikelyDuplicate, path_str.to_string()));
+            return;
+        }
+    }
+    upload_file(data, path_str, shared_state, context).await;
+}
+
+/// The path below the root, without a leading slash
+fn relative_path<'a>(path: &'a str, root: &str) -> &'a str {
+    path.strip_prefix(root).unwrap_or(path).trim_start_matches('/')
+}
+
+/// The user a file belongs to, which is the first folder below the root if that is an accepted user
+fn username_for(path: &str, root: &str, acceptable_users: &[String]) -> String {
+    match relative_path(path, root).split_once('/') {
+        Some((folder, _)) if acceptable_users.iter().any(|user| user == folder) => folder.to_string(),
+        _ => DEFAULT_UPLOADER.to_string(),
     }
 }
 
@@ -123,14 +367,11 @@ pub(crate) fn read_file(
     path: &str,
     root: &str,
     acceptable_users: &[String],
+    category_rules: &CategoryRules,
+    file_size: u64,
 ) -> Result<PathData, std::fmt::Error> {
 
-    // Split out the root
-    let relative_path: String = path
-        .split(root)
-        .find(|x| !x.is_empty())
-        .unwrap()
-        .to_owned();
+    let relative_path = relative_path(path, root).to_owned();
 
     // create mutable copy to pop out different parts
     let mut mutable_relative_path: Vec<&str> = relative_path
@@ -139,24 +380,16 @@ pub(crate) fn read_file(
 
     let absolute_path = path.to_owned();
     let filename = mutable_relative_path.pop().unwrap().to_owned();
-    let mut username: &str;
-    // If the file is in the root folder set it to default
-    if mutable_relative_path.is_empty() {
-        username = "Default_Uploader";
-    } else {
-        username = mutable_relative_path.remove(0);
-        if !acceptable_users.contains(&username.to_string()) {
-            username = "Default_Uploader";
-        }
+    let username = username_for(path, root, acceptable_users);
+    // The user folder is never a tag, also when it is not an accepted user
+    if !mutable_relative_path.is_empty() {
+        mutable_relative_path.remove(0);
     }
     let tags: Vec<String> = mutable_relative_path.iter().map(|x| x.to_lowercase()).collect();
-    let file_buffer = get_file_buffer(path).unwrap();
-    let file_buffer = Arc::new(file_buffer);
-
-    let username = username.to_owned();
+    let categories = category_rules.categories_for(&mutable_relative_path);
 
-    let extension = FileExtension::from(Path::new(path));
-    let mime_type = extension.mime_type().to_string();
+    // Until the content is sniffed right before the upload, the extension decides the MIME type
+    let mime_type = FileExtension::from(Path::new(path)).mime_type().to_string();
 
     Ok(PathData {
         absolute_path,
@@ -164,30 +397,70 @@ pub(crate) fn read_file(
         filename,
         username,
         tags,
+        categories,
         mime_type,
-        file_buffer,
+        extension_mismatch: None,
+        file_size,
     })
 }
 
-pub fn get_files_in_directory(path: &str) -> io::Result<Vec<PathBuf>> {
+/// Every file with a known video extension below the path, with its size
+pub fn get_files_in_directory(path: &str) -> io::Result<Vec<(PathBuf, u64)>> {
     let path = Path::new(path);
-    let mut file_paths = Vec::new();
+    let mut files = Vec::new();
 
     for entry in read_dir(path)? {
         let entry = entry?;
         let current_path = entry.path();
+        // Follows links like the library folders may contain, and skips the ones that are broken
+        let Ok(metadata) = fs::metadata(&current_path) else {
+            continue;
+        };
 
-        if current_path.is_file() {
+        if metadata.is_file() {
             match FileExtension::from(&current_path) {
                 FileExtension::Unknown => {}
                 _ => {
-                    file_paths.push(current_path)
+                    files.push((current_path, metadata.len()))
                 }
             }
-        } else if current_path.is_dir() {
+        } else if metadata.is_dir() {
             let mut sub_files = get_files_in_directory(current_path.as_path().to_str().unwrap().trim())?;
-            file_paths.append(&mut sub_files);
+            files.append(&mut sub_files);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::CategoryConfig;
+    use super::*;
+
+    #[test]
+    fn username_is_first_folder_if_accepted() {
+        let users = vec![String::from("alice")];
+        assert_eq!(username_for("/library/alice/trips/a.mp4", "/library", &users), "alice");
+        assert_eq!(username_for("/library/alice/a.mp4", "/library/", &users), "alice");
+    }
+
+    #[test]
+    fn other_files_go_to_default_uploader() {
+        let users = vec![String::from("alice")];
+        assert_eq!(username_for("/library/bob/a.mp4", "/library", &users), DEFAULT_UPLOADER);
+        assert_eq!(username_for("/library/alice.mp4", "/library", &users), DEFAULT_UPLOADER);
+    }
+
+    #[test]
+    fn read_file_splits_the_same_way_with_or_without_trailing_slash() {
+        let users = vec![String::from("alice")];
+        let rules = CategoryRules::from_config(&CategoryConfig::default()).unwrap();
+        for root in ["/library", "/library/"] {
+            let data = read_file("/library/alice/Trips/a.mp4", root, &users, &rules, 1).unwrap();
+            assert_eq!(data.relative_path, "alice/Trips/a.mp4");
+            assert_eq!(data.username, "alice");
+            assert_eq!(data.tags, vec![String::from("trips")]);
+            assert_eq!(data.filename, "a.mp4");
         }
     }
-    Ok(file_paths)
 }
@@ -1,54 +1,27 @@
 use std::fs::File;
 use std::{io, process};
-use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::future::Future;
+use std::pin::Pin;
 use std::process::Command;
 use std::sync::{Arc, Mutex};
 use crossterm::style::Stylize;
-use reqwest::Client;
+use tokio::time::Instant;
+use crate::follow_ups::{run_steps, FollowUpStep};
 use crate::path_data::PathData;
+use crate::retry_queue::{is_retryable_error, RetryDecision};
 use crate::shared_state::SharedState;
 use crate::tree_node;
 use crate::tree_node::find_unique_files_in_directory;
+use crate::upload_context::UploadContext;
 use crate::upload_status::UploadStatus;
-
-pub fn compute_md5_hash(buffer: &Vec<u8>) -> io::Result<String> {
-    let digest = md5::compute(buffer);
-    Ok(format!("{:x}", digest))
-}
-
-pub fn get_file_buffer(path: &str) -> Result<Vec<u8>, io::Error> {
-    let path = Path::new(path);
-    let mut file = File::open(path)?;
-    let mut buffer = Vec::new();
-    let _ = file.read_to_end(&mut buffer);
-    Ok(buffer)
-}
+use crate::upload_target::{EncodingStatus, UploadedMedia};
 
 pub fn get_file_size(path: &Path) -> io::Result<u64> {
     let file = File::open(path)?;
     Ok(file.metadata()?.len())
 }
 
-pub fn compute_hash_of_partial_file(path: &Path) -> io::Result<String> {
-    const CHUNK_SIZE: usize = 128 * 1024; // 128 KB in bytes
-    let mut file = File::open(path)?;
-
-    // Read the first 128KB
-    let mut chunk = vec![0; CHUNK_SIZE];
-    file.read_exact(&mut chunk)?;
-    let file_size = file.metadata()?.len();
-    let file_size_str = file_size.to_string(); // required to match MediaCMS' Python implementation
-    let file_size_bytes = file_size_str.as_bytes();
-
-    // Combine the chunk and file size for the final hash
-    let mut buffer = chunk;
-    buffer.extend_from_slice(file_size_bytes);
-
-    let result = compute_md5_hash(&buffer).unwrap();
-    Ok(result)
-}
-
 pub fn check_file_integrity(path: &PathBuf) -> bool {
     let output = Command::new("ffprobe")
         .arg("-v")
@@ -62,36 +35,129 @@ pub fn check_file_integrity(path: &PathBuf) -> bool {
 
 pub async fn upload_file(
     data: Result<PathData, core::fmt::Error>,
-    client: &Client,
     path_str: &str,
     shared_state: Arc<Mutex<SharedState>>,
+    context: &Arc<UploadContext>,
 ) {
-    if let Ok(data) = data {
-        match data.upload(client).await {
-            Ok(response) => {
-                if response.status() == 201 {
-                    shared_state
-                        .lock()
-                        .unwrap()
-                        .append_to_processed_files((UploadStatus::Success, path_str.to_string()));
-                } else {
-                    shared_state
-                        .lock()
-                        .unwrap()
-                        .append_to_processed_files((UploadStatus::Failed(response.status().as_u16()), path_str.to_string()));
-                }
+    if let Ok(mut data) = data {
+        data.sniff_container();
+        context.encoding_gate.wait_until_open().await;
+        context.bandwidth.wait_until_unpaused().await;
+        if let Some((extension, container)) = data.extension_mismatch {
+            shared_state.lock().unwrap().append_to_mismatched_files(path_str.to_string(), extension, container);
+        }
+        let progress = shared_state.lock().unwrap().append_to_currently_uploading(path_str.to_string(), data.file_size);
+        let start_time = Instant::now();
+        let bandwidth_waits = context.bandwidth.wait_count();
+        let result = context.target.upload(&data, &progress).await;
+        // Any upload held back meanwhile means the total bandwidth, not the server, set the pace
+        let elapsed = (context.bandwidth.wait_count() == bandwidth_waits).then(|| start_time.elapsed());
+        // Status code of the failure and whether it is worth trying again
+        let (failure, media) = match result {
+            Ok(media) => (None, Some(media)),
+            Err(error) => (Some((error.status_code(), is_retryable_error(&error))), None),
+        };
+        match failure {
+            None => context.limiter.on_success(elapsed, data.file_size),
+            Some((_, true)) => context.limiter.on_overload(),
+            Some((_, false)) => {}
+        }
+        let status = match failure {
+            None => {
+                context.retry_queue.remove(path_str);
+                UploadStatus::Success
             }
-            Err(error) => {
-                shared_state
-                    .lock()
-                    .unwrap()
-                    .append_to_processed_files((UploadStatus::Failed(error.status().unwrap().as_u16()), path_str.to_string()));
+            Some((status_code, true)) => match context.retry_queue.schedule(path_str, status_code) {
+                RetryDecision::Retry => UploadStatus::Retrying(status_code),
+                RetryDecision::GiveUp => UploadStatus::Failed(status_code),
+            },
+            Some((status_code, false)) => {
+                context.retry_queue.remove(path_str);
+                UploadStatus::Failed(status_code)
             }
         };
-        shared_state.lock().unwrap().remove_from_currently_uploading(path_str.to_string());
+        if let Some(media) = &media {
+            if let Some(detector) = &context.near_duplicates {
+                detector.mark_uploaded(path_str);
+            }
+            // Steps left over for media made from this file before, like after a failed encode, no longer apply
+            context.follow_ups.remove_path(path_str);
+            let steps = context.target.follow_up_steps(&data.username);
+            run_follow_ups(&data, media, &steps, path_str, &shared_state, context).await;
+        }
+        let mut state = shared_state.lock().unwrap();
+        state.set_upload_limit(context.limiter.limit(), context.limiter.max_limit());
+        if let (Some(monitor), Some(media)) = (&context.encoding_monitor, media) {
+            // The file only counts as processed once its encoding is done
+            state.start_awaiting_encoding(path_str.to_string());
+            monitor.start_following();
+            tokio::spawn(follow_encoding(data, media, path_str.to_string(), shared_state.clone(), context.clone()));
+            return;
+        }
+        state.append_to_processed_files((status, path_str.to_string()));
+        state.remove_from_currently_uploading(path_str.to_string());
     }
 }
 
+/// Runs the steps that finish uploaded media. Steps that fail are queued to be tried again on their
+/// own, as the media exists and uploading the file again would only create a duplicate.
+pub async fn run_follow_ups(
+    data: &PathData,
+    media: &UploadedMedia,
+    steps: &[FollowUpStep],
+    path: &str,
+    shared_state: &Arc<Mutex<SharedState>>,
+    context: &UploadContext,
+) {
+    match run_steps(context.target.as_ref(), data, media, steps).await {
+        None => {
+            context.follow_ups.remove(media);
+            shared_state.lock().unwrap().remove_from_incomplete_media(path);
+        }
+        Some((failed, error)) => {
+            let failed_steps = failed.iter().map(FollowUpStep::to_string).collect::<Vec<_>>().join(", ");
+            let retrying = matches!(context.follow_ups.schedule(media, path, failed, &error), RetryDecision::Retry);
+            shared_state.lock().unwrap().append_to_incomplete_media(path.to_string(), format!("Could not {}: {}", failed_steps, error), retrying);
+        }
+    }
+}
+
+/// Waits for the server to encode an uploaded file, and deletes and uploads it again if encoding
+/// failed and the policy allows it. Boxed, as it calls `upload_file` which spawns it again.
+fn follow_encoding(
+    data: PathData,
+    media: UploadedMedia,
+    path: String,
+    shared_state: Arc<Mutex<SharedState>>,
+    context: Arc<UploadContext>,
+) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    Box::pin(async move {
+        let Some(monitor) = &context.encoding_monitor else {
+            return;
+        };
+        let encoding_status = monitor.wait_for_encoding(context.target.as_ref(), &data, &media).await;
+        shared_state.lock().unwrap().stop_awaiting_encoding(&path);
+
+        match encoding_status {
+            EncodingStatus::Failed if monitor.should_reupload(&path) => {
+                shared_state.lock().unwrap().append_to_processed_files((UploadStatus::Reuploading, path.clone()));
+                // A failed delete leaves the broken media behind, but the file is still uploaded again
+                let _ = context.target.delete(&data, &media).await;
+                let _permit = context.limiter.acquire().await;
+                upload_file(Ok(data), &path, shared_state, &context).await;
+            }
+            EncodingStatus::Failed => {
+                shared_state.lock().unwrap().append_to_processed_files((UploadStatus::EncodeFailed, path));
+            }
+            // Media that is still pending after the maximum wait was received fine, so it counts as uploaded
+            EncodingStatus::Success | EncodingStatus::Pending => {
+                shared_state.lock().unwrap().append_to_processed_files((UploadStatus::Success, path));
+            }
+        }
+        monitor.stop_following();
+    })
+}
+
 pub fn get_newest_files(root_folder: &str) -> Vec<PathBuf> {
     match tree_node::load_tree_from_file("tree.json") {
         Ok(old_node) => {
@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use crate::config::RetryConfig;
+use crate::path_data::{PathData, UploadError};
+use crate::retry_queue::{backoff, is_retryable_status, RetryDecision};
+use crate::upload_target::{UploadedMedia, UploadTarget};
+
+/// Something done to media after it was created. A step that fails is tried again on its own, as
+/// uploading the file again would create a second copy of the media.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FollowUpStep {
+    /// Title, tags and categories, through `UploadTarget::update_metadata`
+    Metadata,
+    Playlist,
+    Subtitles,
+    Poster,
+}
+
+impl Display for FollowUpStep {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            FollowUpStep::Metadata => "update metadata",
+            FollowUpStep::Playlist => "add to playlist",
+            FollowUpStep::Subtitles => "add subtitles",
+            FollowUpStep::Poster => "set poster",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FollowUpEntry {
+    /// The file the media was uploaded from
+    pub path: String,
+    pub steps: Vec<FollowUpStep>,
+    pub attempts: u32,
+    pub last_error: String,
+    /// When the steps may be tried again. Only set for steps that are retried during this run.
+    #[serde(skip)]
+    pub next_attempt: Option<Instant>,
+}
+
+/// Steps that failed after their media was created, keyed by media id. Like the retry queue, it is
+/// mirrored to disk so the next run tries the steps that were still left.
+pub struct FollowUpQueue {
+    file_path: String,
+    config: RetryConfig,
+    entries: Mutex<HashMap<String, FollowUpEntry>>,
+}
+
+impl FollowUpQueue {
+    pub fn load(config: &RetryConfig) -> FollowUpQueue {
+        let mut entries: HashMap<String, FollowUpEntry> = fs::read_to_string(&config.follow_up_file)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        // Leftovers from an earlier run are tried right away, with a fresh set of attempts
+        let now = Instant::now();
+        for entry in entries.values_mut() {
+            entry.attempts = 0;
+            entry.next_attempt = Some(now);
+        }
+        FollowUpQueue {
+            file_path: config.follow_up_file.clone(),
+            config: config.clone(),
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Stores the steps that failed, to be tried again if the error is transient and attempts remain
+    pub fn schedule(&self, media: &UploadedMedia, path: &str, steps: Vec<FollowUpStep>, error: &UploadError) -> RetryDecision {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(media.id.clone()).or_insert(FollowUpEntry {
+            path: path.to_string(),
+            steps: vec![],
+            attempts: 0,
+            last_error: String::new(),
+            next_attempt: None,
+        });
+        entry.steps = steps;
+        entry.attempts += 1;
+        entry.last_error = error.to_string();
+
+        let decision = if is_retryable(error) && entry.attempts < self.config.max_attempts {
+            entry.next_attempt = Some(Instant::now() + backoff(&self.config, entry.attempts));
+            RetryDecision::Retry
+        } else {
+            // Keep the entry on disk so the next run tries it again
+            entry.next_attempt = None;
+            RetryDecision::GiveUp
+        };
+        self.save(&entries);
+        decision
+    }
+
+    pub fn remove(&self, media: &UploadedMedia) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.remove(&media.id).is_some() {
+            self.save(&entries);
+        }
+    }
+
+    /// Drops the steps of every media made from the file, for when it is uploaded again
+    pub fn remove_path(&self, path: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        let count = entries.len();
+        entries.retain(|_, entry| entry.path != path);
+        if entries.len() != count {
+            self.save(&entries);
+        }
+    }
+
+    /// Takes the steps of every media that is waiting for another attempt in this run, with the time it is due
+    pub fn take_pending(&self) -> Vec<(UploadedMedia, FollowUpEntry, Instant)> {
+        let mut entries = self.entries.lock().unwrap();
+        entries
+            .iter_mut()
+            .filter_map(|(id, entry)| {
+                let due = entry.next_attempt.take()?;
+                Some((UploadedMedia { id: id.clone() }, entry.clone(), due))
+            })
+            .collect()
+    }
+
+    fn save(&self, entries: &HashMap<String, FollowUpEntry>) {
+        if let Ok(serialized) = serde_json::to_string_pretty(entries) {
+            let _ = fs::write(&self.file_path, serialized);
+        }
+    }
+}
+
+/// Runs every step, carrying on after a failure as the steps do not depend on each other. Returns
+/// the steps that failed with the last error.
+pub async fn run_steps(
+    target: &dyn UploadTarget,
+    data: &PathData,
+    media: &UploadedMedia,
+    steps: &[FollowUpStep],
+) -> Option<(Vec<FollowUpStep>, UploadError)> {
+    let mut failed = vec![];
+    let mut last_error = None;
+    for step in steps {
+        if let Err(error) = target.follow_up(data, media, *step).await {
+            failed.push(*step);
+            last_error = Some(error);
+        }
+    }
+    last_error.map(|error| (failed, error))
+}
+
+/// Repeating a step can not create a duplicate the way repeating an upload can, so only errors
+/// that will not go away are given up on
+fn is_retryable(error: &UploadError) -> bool {
+    match error {
+        UploadError::Rejected(status_code) => is_retryable_status(*status_code),
+        UploadError::Request(_) | UploadError::Database(_) | UploadError::Stalled => true,
+        UploadError::Io(_) | UploadError::MissingMedia => false,
+    }
+}
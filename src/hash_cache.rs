@@ -0,0 +1,303 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+use serde::{Deserialize, Serialize};
+use tokio::task;
+use crate::config::HashCacheConfig;
+use crate::hash_strategy::HashStrategy;
+
+/// What identifies a version of a file. A change to any of it means the content may have changed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FileStamp {
+    size: u64,
+    modified_secs: i64,
+    modified_nanos: u32,
+    inode: u64,
+}
+
+impl FileStamp {
+    pub(crate) fn read(path: &Path) -> io::Result<FileStamp> {
+        let metadata = fs::metadata(path)?;
+        let (modified_secs, modified_nanos) = match metadata.modified()?.duration_since(UNIX_EPOCH) {
+            Ok(since_epoch) => (since_epoch.as_secs() as i64, since_epoch.subsec_nanos()),
+            Err(error) => (-(error.duration().as_secs() as i64), error.duration().subsec_nanos()),
+        };
+        Ok(FileStamp {
+            size: metadata.len(),
+            modified_secs,
+            modified_nanos,
+            inode: inode(&metadata),
+        })
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CachedFile {
+    stamp: FileStamp,
+    /// Hashes of the file, keyed by the id of the strategy that made them
+    hashes: HashMap<String, String>,
+}
+
+/// One line of the cache file. Later lines for the same path replace earlier ones.
+#[derive(Serialize, Deserialize)]
+struct LogLine {
+    path: String,
+    #[serde(flatten)]
+    file: CachedFile,
+}
+
+struct CacheState {
+    files: HashMap<String, CachedFile>,
+    /// New hashes are appended to the file, so nothing is rewritten while files are hashed
+    log: Option<BufWriter<File>>,
+    /// Whether the file has lines that are outdated or for files that are gone
+    needs_compaction: bool,
+}
+
+/// Hashes computed by earlier runs, keyed by absolute path, so unchanged files are not read again
+pub struct HashCache {
+    /// Not kept on disk if `None`
+    file_path: Option<String>,
+    state: Mutex<CacheState>,
+}
+
+impl HashCache {
+    pub fn load(config: &HashCacheConfig) -> HashCache {
+        let mut files = HashMap::new();
+        let mut lines = 0;
+        let mut broken = false;
+        if config.enabled {
+            if let Ok(file) = File::open(&config.file) {
+                for line in BufReader::new(file).lines().map_while(Result::ok) {
+                    match serde_json::from_str(&line) {
+                        Ok(LogLine { path, file }) => {
+                            files.insert(path, file);
+                            lines += 1;
+                        }
+                        Err(_) => broken = true,
+                    }
+                }
+            }
+        }
+        let log = if config.enabled {
+            OpenOptions::new().create(true).append(true).open(&config.file).ok().map(BufWriter::new)
+        } else {
+            None
+        };
+        let cache = HashCache {
+            file_path: config.enabled.then(|| config.file.clone()),
+            state: Mutex::new(CacheState {
+                needs_compaction: lines > files.len(),
+                files,
+                log,
+            }),
+        };
+        // A line cut off by a crash only loses that hash, but new lines must not be appended to it
+        if broken {
+            cache.compact(&mut cache.state.lock().unwrap());
+        }
+        cache
+    }
+
+    /// Returns the cached hash of the file if it has not changed since, or computes and caches it
+    pub fn get_or_compute(&self, path: &Path, strategy: &dyn HashStrategy) -> io::Result<String> {
+        let kind = strategy.id();
+        if self.file_path.is_none() {
+            return strategy.compute(path);
+        }
+        let key = path.to_string_lossy().to_string();
+        let stamp = FileStamp::read(path)?;
+        {
+            let state = self.state.lock().unwrap();
+            if let Some(hash) = state.files
+                .get(&key)
+                .filter(|cached| cached.stamp == stamp)
+                .and_then(|cached| cached.hashes.get(kind))
+            {
+                return Ok(hash.clone());
+            }
+        }
+
+        // Hashed without holding the lock, so other files can be looked up meanwhile
+        let hash = strategy.compute(path)?;
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let cached = state.files.entry(key.clone()).or_insert_with(|| CachedFile {
+            stamp: stamp.clone(),
+            hashes: HashMap::new(),
+        });
+        if cached.stamp != stamp {
+            // The file changed, so none of its other hashes can be trusted
+            cached.stamp = stamp;
+            cached.hashes.clear();
+        }
+        if !cached.hashes.is_empty() {
+            state.needs_compaction = true;
+        }
+        cached.hashes.insert(kind.to_string(), hash.clone());
+        if let Some(log) = &mut state.log {
+            let line = LogLine { path: key, file: cached.clone() };
+            if let Ok(serialized) = serde_json::to_string(&line) {
+                let _ = writeln!(log, "{}", serialized);
+            }
+        }
+        Ok(hash)
+    }
+
+    /// Like `get_or_compute`, but reads the file on a blocking thread so async tasks can await it
+    pub async fn get_or_compute_async(
+        self: &Arc<Self>,
+        path: &Path,
+        strategy: &'static dyn HashStrategy,
+    ) -> io::Result<String> {
+        let cache = self.clone();
+        let path = path.to_path_buf();
+        task::spawn_blocking(move || cache.get_or_compute(&path, strategy))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Drops files that are no longer in the library
+    pub fn retain(&self, paths: &[PathBuf]) {
+        let existing: HashSet<String> = paths
+            .iter()
+            .map(|path| path.to_string_lossy().to_string())
+            .collect();
+        let mut state = self.state.lock().unwrap();
+        let count = state.files.len();
+        state.files.retain(|path, _| existing.contains(path));
+        if state.files.len() != count {
+            state.needs_compaction = true;
+        }
+    }
+
+    /// Writes out the appended hashes, and rewrites the file without outdated lines if it has any
+    pub fn flush(&self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(log) = &mut state.log {
+            let _ = log.flush();
+        }
+        if state.needs_compaction {
+            self.compact(&mut state);
+        }
+    }
+
+    fn compact(&self, state: &mut CacheState) {
+        let Some(file_path) = &self.file_path else {
+            return;
+        };
+        // Written next to the cache and renamed over it, so a crash mid-write can not corrupt it
+        let temporary_path = format!("{}.tmp", file_path);
+        let written = File::create(&temporary_path).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            for (path, file) in &state.files {
+                let line = LogLine { path: path.clone(), file: file.clone() };
+                writeln!(writer, "{}", serde_json::to_string(&line)?)?;
+            }
+            writer.flush()
+        });
+        if written.is_ok() && fs::rename(&temporary_path, file_path).is_ok() {
+            state.needs_compaction = false;
+            state.log = OpenOptions::new().append(true).open(file_path).ok().map(BufWriter::new);
+        }
+    }
+}
+
+impl Drop for HashCache {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::*;
+
+    /// Counts how often a file is actually read
+    struct CountingStrategy(AtomicUsize);
+
+    impl HashStrategy for CountingStrategy {
+        fn id(&self) -> &'static str {
+            "counting"
+        }
+
+        fn compute(&self, path: &Path) -> io::Result<String> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            fs::read_to_string(path)
+        }
+    }
+
+    fn test_config(name: &str) -> (HashCacheConfig, PathBuf) {
+        let directory = env::temp_dir().join(format!("media_uploader_hash_cache_{}_{}", name, process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        fs::create_dir_all(&directory).unwrap();
+        let config = HashCacheConfig {
+            enabled: true,
+            file: directory.join("cache.jsonl").to_string_lossy().to_string(),
+        };
+        (config, directory)
+    }
+
+    #[test]
+    fn hashes_are_kept_between_runs() {
+        let (config, directory) = test_config("reload");
+        let video = directory.join("video.mp4");
+        fs::write(&video, "content").unwrap();
+        let strategy = CountingStrategy(AtomicUsize::new(0));
+
+        {
+            let cache = HashCache::load(&config);
+            assert_eq!(cache.get_or_compute(&video, &strategy).unwrap(), "content");
+            assert_eq!(cache.get_or_compute(&video, &strategy).unwrap(), "content");
+        }
+        let cache = HashCache::load(&config);
+        assert_eq!(cache.get_or_compute(&video, &strategy).unwrap(), "content");
+        drop(cache);
+        let _ = fs::remove_dir_all(&directory);
+
+        assert_eq!(strategy.0.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn cut_off_line_is_skipped_and_compacted() {
+        let (config, directory) = test_config("broken");
+        let video = directory.join("video.mp4");
+        fs::write(&video, "content").unwrap();
+        let strategy = CountingStrategy(AtomicUsize::new(0));
+        {
+            let cache = HashCache::load(&config);
+            cache.get_or_compute(&video, &strategy).unwrap();
+        }
+        let mut contents = fs::read_to_string(&config.file).unwrap();
+        contents.push_str("{\"path\":\"/cut");
+        fs::write(&config.file, contents).unwrap();
+
+        let cache = HashCache::load(&config);
+        cache.get_or_compute(&video, &strategy).unwrap();
+        drop(cache);
+        let lines = fs::read_to_string(&config.file).unwrap().lines().count();
+        let _ = fs::remove_dir_all(&directory);
+
+        assert_eq!(strategy.0.load(Ordering::Relaxed), 1);
+        assert_eq!(lines, 1);
+    }
+}
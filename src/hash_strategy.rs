@@ -0,0 +1,239 @@
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::config::HashScheme;
+
+/// Bytes hashed by the partial hash, the same as `dd bs=1 count=131072` in the patched `models.py`
+const PARTIAL_SIZE: u64 = 128 * 1024;
+const READ_CHUNK_SIZE: usize = 1024 * 1024; // 1 MB read from disk at a time
+
+pub const PARTIAL_MD5: &dyn HashStrategy = &PartialMd5;
+pub const FULL_MD5: &dyn HashStrategy = &FullMd5;
+pub const SHA256: &dyn HashStrategy = &Sha256Hash;
+pub const BLAKE3: &dyn HashStrategy = &Blake3Hash;
+
+const STRATEGIES: [&dyn HashStrategy; 4] = [PARTIAL_MD5, FULL_MD5, SHA256, BLAKE3];
+
+/// A way of hashing files. The id is stored with every hash, so hashes made in different ways are
+/// never compared, and stored hashes keep working after the scheme is changed.
+pub trait HashStrategy: Send + Sync {
+    /// Never changes once hashes have been stored with it
+    fn id(&self) -> &'static str;
+
+    fn compute(&self, path: &Path) -> io::Result<String>;
+}
+
+/// The strategy a stored hash was made with, if this version knows it
+pub fn strategy_by_id(id: &str) -> Option<&'static dyn HashStrategy> {
+    STRATEGIES.into_iter().find(|strategy| strategy.id() == id)
+}
+
+impl HashScheme {
+    pub fn strategy(self) -> &'static dyn HashStrategy {
+        match self {
+            HashScheme::PartialMd5 => PARTIAL_MD5,
+            HashScheme::Md5 => FULL_MD5,
+            HashScheme::Sha256 => SHA256,
+            HashScheme::Blake3 => BLAKE3,
+        }
+    }
+}
+
+/// A hash together with the id of the strategy that made it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StoredHash {
+    pub scheme: String,
+    pub value: String,
+}
+
+impl StoredHash {
+    pub fn new(strategy: &dyn HashStrategy, value: String) -> StoredHash {
+        StoredHash {
+            scheme: strategy.id().to_string(),
+            value,
+        }
+    }
+
+    /// The strategy this hash was made with, if this version knows it
+    pub fn strategy(&self) -> Option<&'static dyn HashStrategy> {
+        strategy_by_id(&self.scheme)
+    }
+}
+
+/// MD5 of the first 128 KB followed by the file size as text, which is fast on large files. Files
+/// shorter than that are hashed whole, as `dd` does.
+pub struct PartialMd5;
+
+impl HashStrategy for PartialMd5 {
+    fn id(&self) -> &'static str {
+        "partial_md5"
+    }
+
+    fn compute(&self, path: &Path) -> io::Result<String> {
+        let mut file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+
+        let mut buffer = Vec::new();
+        file.by_ref().take(PARTIAL_SIZE).read_to_end(&mut buffer)?;
+        // required to match MediaCMS' Python implementation
+        buffer.extend_from_slice(file_size.to_string().as_bytes());
+
+        Ok(format!("{:x}", md5::compute(&buffer)))
+    }
+}
+
+/// MD5 of the whole file, as stock MediaCMS stores it
+pub struct FullMd5;
+
+impl HashStrategy for FullMd5 {
+    fn id(&self) -> &'static str {
+        "md5"
+    }
+
+    fn compute(&self, path: &Path) -> io::Result<String> {
+        let mut context = md5::Context::new();
+        read_in_chunks(path, |chunk| context.consume(chunk))?;
+        Ok(format!("{:x}", context.compute()))
+    }
+}
+
+pub struct Sha256Hash;
+
+impl HashStrategy for Sha256Hash {
+    fn id(&self) -> &'static str {
+        "sha256"
+    }
+
+    fn compute(&self, path: &Path) -> io::Result<String> {
+        let mut hasher = Sha256::new();
+        read_in_chunks(path, |chunk| hasher.update(chunk))?;
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+}
+
+pub struct Blake3Hash;
+
+impl HashStrategy for Blake3Hash {
+    fn id(&self) -> &'static str {
+        "blake3"
+    }
+
+    fn compute(&self, path: &Path) -> io::Result<String> {
+        let mut hasher = blake3::Hasher::new();
+        read_in_chunks(path, |chunk| {
+            hasher.update(chunk);
+        })?;
+        Ok(hasher.finalize().to_hex().to_string())
+    }
+}
+
+/// Reads the whole file in fixed size chunks, so memory use does not grow with the file size
+fn read_in_chunks(path: &Path, mut consume: impl FnMut(&[u8])) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut chunk = vec![0; READ_CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut chunk)?;
+        if read == 0 {
+            return Ok(());
+        }
+        consume(&chunk[..read]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use super::*;
+
+    fn write_temp(name: &str, contents: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("media_uploader_hash_{}_{}", name, process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// What `dd bs=1 count=131072; echo -n <size>` piped into md5 gives on the server
+    fn server_hash(contents: &[u8]) -> String {
+        let mut input = contents[..contents.len().min(PARTIAL_SIZE as usize)].to_vec();
+        input.extend_from_slice(contents.len().to_string().as_bytes());
+        format!("{:x}", md5::compute(&input))
+    }
+
+    fn partial_md5(name: &str, contents: &[u8]) -> String {
+        let path = write_temp(name, contents);
+        let hash = PARTIAL_MD5.compute(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        hash
+    }
+
+    #[test]
+    fn partial_md5_hashes_short_files_whole() {
+        // md5 of "hello5"
+        assert_eq!(partial_md5("short", b"hello"), "ebde9cc9540087b9688fdb470fa20f17");
+    }
+
+    #[test]
+    fn partial_md5_hashes_empty_files() {
+        // md5 of "0"
+        assert_eq!(partial_md5("empty", b""), "cfcd208495d565ef66e7dff9f98764da");
+    }
+
+    #[test]
+    fn partial_md5_hashes_exactly_128_kb() {
+        let contents = vec![b'a'; PARTIAL_SIZE as usize];
+        assert_eq!(partial_md5("exact", &contents), "27cd69f27e214d9560fbb62bfc69d32f");
+    }
+
+    #[test]
+    fn partial_md5_only_reads_the_first_128_kb_of_longer_files() {
+        let contents: Vec<u8> = (0..300 * 1024).map(|index| (index % 251) as u8).collect();
+        assert_eq!(partial_md5("long", &contents), server_hash(&contents));
+
+        // Bytes after the first 128 KB do not change the hash, the size does
+        let mut changed_tail = contents.clone();
+        *changed_tail.last_mut().unwrap() ^= 0xFF;
+        assert_eq!(partial_md5("long_tail", &changed_tail), server_hash(&contents));
+        assert_ne!(partial_md5("long_size", &contents[..contents.len() - 1]), server_hash(&contents));
+    }
+
+    #[test]
+    fn full_hashes_cover_the_whole_file() {
+        let path = write_temp("full", b"hello");
+        assert_eq!(FULL_MD5.compute(&path).unwrap(), "5d41402abc4b2a76b9719d911017c592");
+        assert_eq!(
+            SHA256.compute(&path).unwrap(),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(BLAKE3.compute(&path).unwrap(), blake3::hash(b"hello").to_hex().to_string());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn strategies_are_found_by_their_id() {
+        for strategy in STRATEGIES {
+            assert_eq!(strategy_by_id(strategy.id()).unwrap().id(), strategy.id());
+        }
+        for scheme in [HashScheme::PartialMd5, HashScheme::Md5, HashScheme::Sha256, HashScheme::Blake3] {
+            assert!(strategy_by_id(scheme.strategy().id()).is_some());
+        }
+        assert!(strategy_by_id("crc32").is_none());
+    }
+
+    #[test]
+    fn stored_hash_round_trips_through_json() {
+        let hash = StoredHash::new(SHA256, String::from("abc"));
+        let serialized = serde_json::to_string(&hash).unwrap();
+        assert_eq!(serialized, r#"{"scheme":"sha256","value":"abc"}"#);
+        let deserialized: StoredHash = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, hash);
+        assert_eq!(deserialized.strategy().unwrap().id(), SHA256.id());
+
+        let unknown = StoredHash { scheme: String::from("crc32"), value: String::from("abc") };
+        assert!(unknown.strategy().is_none());
+    }
+}
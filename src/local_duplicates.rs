@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+use crate::categories::CategoryRules;
+use crate::config::{DuplicatePolicy, LocalDuplicateConfig};
+use crate::file_traversal::read_file;
+use crate::hash_cache::HashCache;
+use crate::hash_strategy::{HashStrategy, FULL_MD5, PARTIAL_MD5};
+use crate::path_data::PathData;
+
+/// Copies of the same file found in the library, for the report
+#[derive(Serialize, Debug)]
+struct DuplicateGroup {
+    file_size: u64,
+    /// The copy that is uploaded, or `None` if all of them are
+    kept: Option<String>,
+    paths: Vec<String>,
+}
+
+/// What to do with the copies of files that are in the library more than once
+#[derive(Default)]
+pub struct DuplicatePlan {
+    /// Copies that are left out, with the path of the copy that is uploaded instead
+    skipped: HashMap<String, String>,
+    /// Copies that are left out in path order, keyed by the path of the copy that is uploaded
+    copies: HashMap<String, Vec<String>>,
+    /// Tags of every copy, keyed by the path of the copy that is uploaded
+    merged_tags: HashMap<String, Vec<String>>,
+}
+
+impl DuplicatePlan {
+    /// The copy that is uploaded instead of this file, if the file is left out
+    pub fn kept_copy_of(&self, path: &str) -> Option<&str> {
+        self.skipped.get(path).map(String::as_str)
+    }
+
+    /// Every copy that is uploaded, with the copies left out for it
+    pub fn groups(&self) -> impl Iterator<Item = (&String, &Vec<String>)> {
+        self.copies.iter()
+    }
+
+    /// Adds the tags of the other copies of this file. A left out copy gets them as well, for when
+    /// it is uploaded in place of the kept copy.
+    pub fn merge_tags(&self, data: &mut PathData) {
+        let kept = self.kept_copy_of(&data.absolute_path).unwrap_or(&data.absolute_path);
+        if let Some(tags) = self.merged_tags.get(kept) {
+            for tag in tags {
+                if !data.tags.contains(tag) {
+                    data.tags.push(tag.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Finds files that are in the library more than once, by size, then partial hash, and then the
+/// whole file if configured. The groups are written to the report file.
+pub fn find_duplicates(
+    config: &LocalDuplicateConfig,
+    paths: &[PathBuf],
+    file_sizes: &HashMap<String, u64>,
+    hash_cache: &HashCache,
+    root: &str,
+    acceptable_users: &[String],
+    category_rules: &CategoryRules,
+) -> DuplicatePlan {
+    let mut by_size: HashMap<u64, Vec<&Path>> = HashMap::new();
+    for path in paths {
+        if let Some(size) = path.to_str().and_then(|path| file_sizes.get(path)) {
+            by_size.entry(*size).or_default().push(path);
+        }
+    }
+
+    let mut groups: Vec<(u64, Vec<&Path>)> = vec![];
+    for (size, candidates) in by_size.into_iter().filter(|(_, candidates)| candidates.len() > 1) {
+        for candidates in group_by_hash(candidates, hash_cache, PARTIAL_MD5) {
+            if config.full_hash {
+                for copies in group_by_hash(candidates, hash_cache, FULL_MD5) {
+                    groups.push((size, copies));
+                }
+            } else {
+                groups.push((size, candidates));
+            }
+        }
+    }
+
+    let mut plan = DuplicatePlan::default();
+    let mut report = vec![];
+    for (file_size, mut copies) in groups {
+        copies.sort();
+        let copies: Vec<PathData> = copies
+            .iter()
+            .filter_map(|path| read_file(path.to_str()?, root, acceptable_users, category_rules, file_size).ok())
+            .collect();
+        let kept = choose_copy(config, &copies);
+
+        if let Some(kept) = kept {
+            for copy in copies.iter().filter(|copy| copy.absolute_path != kept.absolute_path) {
+                plan.skipped.insert(copy.absolute_path.clone(), kept.absolute_path.clone());
+                plan.copies
+                    .entry(kept.absolute_path.clone())
+                    .or_default()
+                    .push(copy.absolute_path.clone());
+            }
+            if config.merge_tags {
+                plan.merged_tags.insert(
+                    kept.absolute_path.clone(),
+                    copies.iter().flat_map(|copy| copy.tags.iter().cloned()).collect(),
+                );
+            }
+        }
+        report.push(DuplicateGroup {
+            file_size,
+            kept: kept.map(|kept| kept.absolute_path.clone()),
+            paths: copies.iter().map(|copy| copy.absolute_path.clone()).collect(),
+        });
+    }
+
+    report.sort_by(|a, b| a.paths.cmp(&b.paths));
+    if let Ok(serialized) = serde_json::to_string_pretty(&report) {
+        let _ = fs::write(&config.report_file, serialized);
+    }
+    plan
+}
+
+/// Splits the files into groups with the same hash, leaving out files that have no copies or can not be read
+fn group_by_hash<'a>(candidates: Vec<&'a Path>, hash_cache: &HashCache, strategy: &dyn HashStrategy) -> Vec<Vec<&'a Path>> {
+    let mut by_hash: HashMap<String, Vec<&Path>> = HashMap::new();
+    for path in candidates {
+        if let Ok(hash) = hash_cache.get_or_compute(path, strategy) {
+            by_hash.entry(hash).or_default().push(path);
+        }
+    }
+    by_hash.into_values().filter(|copies| copies.len() > 1).collect()
+}
+
+/// The copy that is uploaded, with the copies sorted by path
+fn choose_copy<'a>(config: &LocalDuplicateConfig, copies: &'a [PathData]) -> Option<&'a PathData> {
+    match config.policy {
+        DuplicatePolicy::UploadAll => None,
+        DuplicatePolicy::FirstPath => copies.first(),
+        DuplicatePolicy::PreferredUser => config.preferred_users
+            .iter()
+            .find_map(|username| copies.iter().find(|copy| &copy.username == username))
+            .or(copies.first()),
+    }
+}
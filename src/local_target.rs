@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use async_trait::async_trait;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use crate::bandwidth::BandwidthLimiter;
+use crate::follow_ups::FollowUpStep;
+use crate::hash_cache::HashCache;
+use crate::hash_strategy::{HashStrategy, StoredHash};
+use crate::path_data::{PathData, UploadError};
+use crate::upload_target::{EncodingStatus, strategies_of, UploadedMedia, UploadTarget};
+
+const COPY_CHUNK_SIZE: usize = 1024 * 1024; // 1 MB copied at a time
+const SIDECAR_EXTENSION: &str = "json";
+
+/// Metadata written next to every copied file, in place of what MediaCMS would store
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Sidecar {
+    title: String,
+    username: String,
+    tags: Vec<String>,
+    #[serde(default)]
+    categories: Vec<String>,
+    mime_type: String,
+    file_size: u64,
+    hash: StoredHash,
+    source_path: String,
+    uploaded_at: String,
+}
+
+/// Copies files into a local directory, mirroring the layout below the root folder. Useful for
+/// rehearsing a full run without a server.
+pub struct LocalDirectoryTarget {
+    directory: PathBuf,
+    bandwidth: Arc<BandwidthLimiter>,
+    hash_cache: Arc<HashCache>,
+    hash_strategy: &'static dyn HashStrategy,
+    /// Hashes of the files copied by earlier runs, keyed by file size
+    existing: HashMap<u64, Vec<StoredHash>>,
+}
+
+impl LocalDirectoryTarget {
+    pub fn new(
+        directory: &str,
+        hash_cache: Arc<HashCache>,
+        hash_strategy: &'static dyn HashStrategy,
+        bandwidth: Arc<BandwidthLimiter>,
+    ) -> io::Result<LocalDirectoryTarget> {
+        let directory = PathBuf::from(directory);
+        fs::create_dir_all(&directory)?;
+        let mut existing: HashMap<u64, Vec<StoredHash>> = HashMap::new();
+        for sidecar in read_sidecars(&directory)? {
+            existing.entry(sidecar.file_size).or_default().push(sidecar.hash);
+        }
+        Ok(LocalDirectoryTarget { directory, bandwidth, hash_cache, hash_strategy, existing })
+    }
+
+    fn destination(&self, data: &PathData) -> PathBuf {
+        self.directory.join(data.relative_path.trim_start_matches('/'))
+    }
+
+    async fn write_sidecar(&self, data: &PathData, destination: &Path) -> Result<(), UploadError> {
+        let hash = self.hash_cache
+            .get_or_compute_async(Path::new(&data.absolute_path), self.hash_strategy)
+            .await?;
+        let sidecar = Sidecar {
+            title: data.filename.clone(),
+            username: data.username.clone(),
+            tags: data.tags.clone(),
+            categories: data.categories.clone(),
+            mime_type: data.mime_type.clone(),
+            file_size: data.file_size,
+            hash: StoredHash::new(self.hash_strategy, hash),
+            source_path: data.absolute_path.clone(),
+            uploaded_at: Local::now().to_rfc3339(),
+        };
+        let serialized = serde_json::to_string_pretty(&sidecar)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        fs::write(sidecar_path(destination), serialized)?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UploadTarget for LocalDirectoryTarget {
+    fn media_count(&self) -> usize {
+        self.existing.values().map(Vec::len).sum()
+    }
+
+    fn hash_strategies(&self, _username: &str, file_size: u64) -> Vec<&'static dyn HashStrategy> {
+        self.existing
+            .get(&file_size)
+            .map(|hashes| strategies_of(hashes.iter()))
+            .unwrap_or_default()
+    }
+
+    fn contains(&self, _username: &str, file_size: u64, hash: &StoredHash) -> bool {
+        self.existing
+            .get(&file_size)
+            .is_some_and(|hashes| hashes.contains(hash))
+    }
+
+    async fn upload(&self, data: &PathData, sent_bytes: &Arc<AtomicU64>) -> Result<UploadedMedia, UploadError> {
+        let destination = self.destination(data);
+        if let Some(parent) = destination.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // Copy in chunks so the bandwidth limit and progress behave like a real upload
+        let mut source = File::open(&data.absolute_path).await?;
+        let mut target = File::create(&destination).await?;
+        let mut buffer = vec![0; COPY_CHUNK_SIZE];
+        loop {
+            let read = source.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            self.bandwidth.consume(read, None).await;
+            target.write_all(&buffer[..read]).await?;
+            sent_bytes.fetch_add(read as u64, Ordering::Relaxed);
+        }
+        target.flush().await?;
+
+        self.write_sidecar(data, &destination).await?;
+        Ok(UploadedMedia { id: destination.to_string_lossy().to_string() })
+    }
+
+    fn follow_up_steps(&self, _username: &str) -> Vec<FollowUpStep> {
+        // The sidecar is written with the copy, so nothing is left to do
+        vec![]
+    }
+
+    async fn follow_up(&self, _data: &PathData, _media: &UploadedMedia, _step: FollowUpStep) -> Result<(), UploadError> {
+        Ok(())
+    }
+
+    async fn update_metadata(&self, data: &PathData, media: &UploadedMedia) -> Result<(), UploadError> {
+        let destination = PathBuf::from(&media.id);
+        if !destination.is_file() {
+            return Err(UploadError::MissingMedia);
+        }
+        self.write_sidecar(data, &destination).await
+    }
+
+    async fn encoding_status(&self, _data: &PathData, _media: &UploadedMedia) -> Result<EncodingStatus, UploadError> {
+        // Nothing is encoded, the copy is final
+        Ok(EncodingStatus::Success)
+    }
+
+    async fn delete(&self, _data: &PathData, media: &UploadedMedia) -> Result<(), UploadError> {
+        let destination = PathBuf::from(&media.id);
+        tokio::fs::remove_file(sidecar_path(&destination)).await?;
+        tokio::fs::remove_file(&destination).await?;
+        Ok(())
+    }
+}
+
+fn sidecar_path(destination: &Path) -> PathBuf {
+    let mut file_name = destination.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(SIDECAR_EXTENSION);
+    destination.with_file_name(file_name)
+}
+
+/// Every sidecar below `directory`. Sidecars that cannot be parsed are ignored.
+fn read_sidecars(directory: &Path) -> io::Result<Vec<Sidecar>> {
+    let mut sidecars = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            sidecars.append(&mut read_sidecars(&path)?);
+        } else if path.extension().is_some_and(|extension| extension == SIDECAR_EXTENSION) {
+            if let Some(sidecar) = fs::read_to_string(&path)
+                .ok()
+                .and_then(|contents| serde_json::from_str(&contents).ok())
+            {
+                sidecars.push(sidecar);
+            }
+        }
+    }
+    Ok(sidecars)
+}
@@ -1,19 +1,36 @@
 use std::collections::HashMap;
 use std::{env, process};
 use std::io::{stdout, Write};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 use dotenv::dotenv;
-use crate::api::{create_client};
+use sqlx::PgPool;
+use crate::api::{create_client, create_client_without_redirects};
+use crate::auth::CredentialStore;
+use crate::bandwidth::{BandwidthLimiter, BandwidthSchedule};
+use crate::categories::CategoryRules;
+use crate::config::{Config, HashMode, TargetConfig};
 use crate::db::{create_database_pool};
+use chrono::Local;
 use clap::Parser;
+use colored::Colorize;
 use crossterm::{
     terminal::{Clear, ClearType},
     cursor::MoveTo,
     ExecutableCommand,
 };
+use crate::encoding_backlog::{spawn_backlog_monitor, EncodingGate};
+use crate::file_traversal::{DEFAULT_UPLOADER, get_files_in_directory};
 use crate::file_utils::get_newest_files;
+use crate::local_target::LocalDirectoryTarget;
+use crate::hash_cache::HashCache;
+use crate::hash_strategy::{FULL_MD5, PARTIAL_MD5};
+use crate::mediacms_target::{detect_hash_mode, MediaCmsTarget};
+use crate::peertube_target::PeerTubeTarget;
 use crate::shared_state::SharedState;
+use crate::upload_context::UploadContext;
+use crate::upload_target::{TargetRouter, UploadTarget};
 
 mod path_data;
 mod file_traversal;
@@ -25,6 +42,28 @@ mod shared_state;
 mod upload_status;
 mod file_extension;
 mod tree_node;
+mod chunked_upload;
+mod retry_queue;
+mod follow_ups;
+mod hash_cache;
+mod hash_strategy;
+mod local_duplicates;
+mod near_duplicates;
+mod concurrency;
+mod upload_context;
+mod encoding_backlog;
+mod auth;
+mod secrets;
+mod bandwidth;
+mod upload_target;
+mod mediacms_target;
+mod local_target;
+mod peertube_target;
+mod encoding_monitor;
+mod categories;
+mod playlists;
+mod subtitles;
+mod posters;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -45,20 +84,43 @@ async fn main() {
     let config = config::read_config(&args.config).unwrap();
     dotenv().ok();
     let root = env::var("ROOT_FOLDER").expect("ROOT_FOLDER must be set");
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let file_metadata_from_db = if !args.dry {
-        let pool = create_database_pool(&database_url).await.unwrap();
-        match db::get_file_details_from_db(pool).await {
-            Ok(metadata) => {
-                metadata
-            }
-            Err(error) => {
-                println!("Could not get rows from database. Reason:, {}", error);
-                process::exit(1)
+
+    let mut usernames = config.accepted_users.clone();
+    usernames.push(DEFAULT_UPLOADER.to_string());
+
+    // Users with the same target config share one target
+    let mut target_configs: Vec<&TargetConfig> = vec![];
+    let mut user_targets: HashMap<String, usize> = HashMap::new();
+    for username in &usernames {
+        let target_config = config.target_for(username);
+        let index = match target_configs.iter().position(|existing| *existing == target_config) {
+            Some(index) => index,
+            None => {
+                target_configs.push(target_config);
+                target_configs.len() - 1
             }
-        }
+        };
+        user_targets.insert(username.clone(), index);
+    }
+
+    let mediacms_users: Vec<String> = usernames
+        .iter()
+        .filter(|username| *config.target_for(username) == TargetConfig::MediaCms)
+        .cloned()
+        .collect();
+    let has_database = !args.dry && env::var("DATABASE_URL").is_ok();
+    if let Err(error) = config.check_website_features(&mediacms_users, has_database) {
+        println!("{} {}", "Invalid config.".red(), error);
+        process::exit(1)
+    }
+
+    // Only MediaCMS needs its database
+    let uses_mediacms = target_configs.iter().any(|target_config| **target_config == TargetConfig::MediaCms);
+    let pool = if !args.dry && uses_mediacms {
+        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        Some(create_database_pool(&database_url).await.unwrap())
     } else {
-        HashMap::new()
+        None
     };
 
     let shared_state = Arc::new(Mutex::new(SharedState {
@@ -67,28 +129,81 @@ async fn main() {
         corrupt_files_counter: 0,
         remaining_files: i32::MAX,  // example number
         failed_files_counter: 0,
+        retried_files_counter: 0,
+        encode_failed_counter: 0,
+        awaiting_encoding: vec![],
         skipped_files: 0,
+        upload_limit: 0,
+        max_upload_limit: 0,
+        encoding_backlog: None,
+        uploads_paused: false,
         last_processed_files: vec![],
         currently_uploading: vec![],
+        remaining_file_sizes: HashMap::new(),
+        remaining_bytes: 0,
         corrupt_files: vec![],
         failed_files: vec![],
+        encode_failed_files: vec![],
+        outcome_listeners: HashMap::new(),
+        incomplete_media: vec![],
+        mismatched_files: vec![],
     }));
-    shared_state.lock().unwrap().set_files_retrieved(file_metadata_from_db.values().len());
 
-    let client = create_client();
+    let encoding_gate = match (&pool, &config.encoding_backlog) {
+        (Some(pool), Some(backlog_config)) => spawn_backlog_monitor(pool.clone(), backlog_config, shared_state.clone()),
+        _ => EncodingGate::always_open(),
+    };
+
+    let bandwidth_schedule = match BandwidthSchedule::from_config(&config.bandwidth) {
+        Ok(schedule) => schedule,
+        Err(error) => {
+            println!("{} {}", "Invalid bandwidth schedule.".red(), error);
+            process::exit(1)
+        }
+    };
+    let bandwidth = Arc::new(BandwidthLimiter::new(
+        bandwidth_schedule.limit_at(&Local::now()),
+        bandwidth_schedule.lowest_rate(),
+    ));
+
+    let category_rules = match CategoryRules::from_config(&config.categories) {
+        Ok(rules) => rules,
+        Err(error) => {
+            println!("{} {}", "Invalid category rules.".red(), error);
+            process::exit(1)
+        }
+    };
+
+    let hash_cache = Arc::new(HashCache::load(&config.hash_cache));
+    // Walked once, for detecting the hash mode as well as for the upload
+    let files = get_files_in_directory(&root).unwrap_or_default();
+
+    let mut targets = Vec::new();
+    for (index, target_config) in target_configs.iter().enumerate() {
+        let target_usernames: Vec<String> = usernames
+            .iter()
+            .filter(|username| user_targets[*username] == index)
+            .cloned()
+            .collect();
+        targets.push(create_target(target_config, &target_usernames, &config, &files, &pool, &hash_cache, bandwidth.clone()).await);
+    }
+    let target = TargetRouter::new(targets, user_targets);
+    shared_state.lock().unwrap().set_files_retrieved(target.media_count());
+
+    let context = UploadContext::new(Box::new(target), category_rules, hash_cache, &config, encoding_gate, bandwidth.clone());
 
     let shared_state_clone = shared_state.clone();
 
     let newest_files = get_newest_files(root.as_str());
 
-    tokio::spawn(async move {
+    let traversal = tokio::spawn(async move {
         file_traversal::iterate_over_files_and_upload(
             &root,
-            file_metadata_from_db,
-            Arc::new(client),
+            context,
             config,
             &shared_state_clone,
-            newest_files
+            files,
+            newest_files,
         ).await;
     });
 
@@ -102,15 +217,20 @@ async fn main() {
         let minutes = (elapsed.as_secs() % 3600) / 60;
         let seconds = elapsed.as_secs() % 60;
 
+        bandwidth.set_limit(bandwidth_schedule.limit_at(&Local::now()));
+
         stdout.execute(Clear(ClearType::All)).unwrap();
         stdout.execute(MoveTo(0, 0)).unwrap();
 
-        let should_break = {
-            let state = shared_state.lock().unwrap();
-            println!("Runtime: {:02}:{:02}:{:02}\n", hours, minutes, seconds);
+        // Steps after the upload can still be retried once every file is processed
+        let should_break = traversal.is_finished();
+        {
+            let mut state = shared_state.lock().unwrap();
+            state.sample_upload_rates();
+            println!("Runtime: {:02}:{:02}:{:02}", hours, minutes, seconds);
+            println!("Bandwidth: {}\n", bandwidth.limit());
             state.print_status();
-            state.remaining_files == 0
-        };
+        }
 
         stdout.flush().unwrap();
 
@@ -122,3 +242,112 @@ async fn main() {
     }
 
 }
+
+/// Sets up one upload target for the given users, or exits if that is not possible
+async fn create_target(
+    target_config: &TargetConfig,
+    usernames: &[String],
+    config: &Config,
+    files: &[(PathBuf, u64)],
+    pool: &Option<PgPool>,
+    hash_cache: &Arc<HashCache>,
+    bandwidth: Arc<BandwidthLimiter>,
+) -> Box<dyn UploadTarget> {
+    match target_config {
+        TargetConfig::MediaCms => {
+            let file_metadata_from_db = if let Some(pool) = pool {
+                match db::get_file_details_from_db(pool.clone()).await {
+                    Ok(metadata) => {
+                        metadata
+                    }
+                    Err(error) => {
+                        println!("Could not get rows from database. Reason:, {}", error);
+                        process::exit(1)
+                    }
+                }
+            } else {
+                HashMap::new()
+            };
+
+            let hash_mode = match config.hash_mode {
+                // Nothing to compare with in an empty database, and then the mode does not matter either
+                HashMode::Auto if file_metadata_from_db.is_empty() => HashMode::Partial,
+                HashMode::Auto => {
+                    match detect_hash_mode(&file_metadata_from_db, files, hash_cache).await {
+                        Some(hash_mode) => {
+                            println!("{} {:?}", "Detected hash mode:".green(), hash_mode);
+                            hash_mode
+                        }
+                        None => {
+                            println!("{}", "Could not detect the hash mode from the database, using partial hashes.".yellow());
+                            HashMode::Partial
+                        }
+                    }
+                }
+                hash_mode => hash_mode,
+            };
+
+            let client = match create_client(&config.tls, &config.http) {
+                Ok(client) => client,
+                Err(error) => {
+                    println!("{} {}", "Could not set up HTTP client.".red(), error);
+                    process::exit(1)
+                }
+            };
+
+            // Django answers a login with a redirect that carries the session cookie, so it is not followed
+            let auth_client = match create_client_without_redirects(&config.tls, &config.http) {
+                Ok(client) => client,
+                Err(error) => {
+                    println!("{} {}", "Could not set up HTTP client.".red(), error);
+                    process::exit(1)
+                }
+            };
+
+            let credentials = match CredentialStore::resolve(&auth_client, &config.auth, usernames).await {
+                Ok(credentials) => {
+                    println!("{}", "Verified credentials for all users.".green());
+                    credentials
+                }
+                Err(error) => {
+                    println!("{} {}", "Could not verify credentials.".red(), error);
+                    process::exit(1)
+                }
+            };
+
+            let hash_strategy = match hash_mode {
+                HashMode::Full => FULL_MD5,
+                _ => PARTIAL_MD5,
+            };
+            Box::new(MediaCmsTarget::new(client, credentials, config, bandwidth, file_metadata_from_db, hash_strategy, pool.clone()))
+        }
+        TargetConfig::Local { path } => match LocalDirectoryTarget::new(path, hash_cache.clone(), config.hash_scheme.strategy(), bandwidth) {
+            Ok(target) => Box::new(target),
+            Err(error) => {
+                println!("{} {}", "Could not open target directory.".red(), error);
+                process::exit(1)
+            }
+        },
+        TargetConfig::PeerTube(peertube_config) => {
+            // The resumable upload API answers each chunk with 308, which must not be followed as a redirect
+            let client = match create_client_without_redirects(&config.tls, &config.http) {
+                Ok(client) => client,
+                Err(error) => {
+                    println!("{} {}", "Could not set up HTTP client.".red(), error);
+                    process::exit(1)
+                }
+            };
+
+            match PeerTubeTarget::connect(client, peertube_config, config, usernames, hash_cache.clone(), bandwidth).await {
+                Ok(target) => {
+                    println!("{} {}", "Logged in to PeerTube at".green(), peertube_config.url);
+                    Box::new(target)
+                }
+                Err(error) => {
+                    println!("{} {}", "Could not log in to PeerTube.".red(), error);
+                    process::exit(1)
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::Poll;
+use async_trait::async_trait;
+use futures::{stream, StreamExt, TryStreamExt};
+use reqwest::{Body, Client, multipart};
+use serde_json::Value;
+use sqlx::{Pool, Postgres};
+use tokio::fs::File;
+use tokio_util::bytes::BytesMut;
+use tokio_util::codec::{BytesCodec, FramedRead};
+use crate::api::{Activity, send_with_idle_timeout, TransferTimeouts};
+use crate::auth::CredentialStore;
+use crate::bandwidth::BandwidthLimiter;
+use crate::chunked_upload::ChunkedUploader;
+use crate::config::{Config, HashMode};
+use crate::db;
+use crate::follow_ups::FollowUpStep;
+use crate::hash_cache::HashCache;
+use crate::hash_strategy::{HashStrategy, StoredHash, FULL_MD5, PARTIAL_MD5};
+use crate::path_data::{PathData, UploadError};
+use crate::playlists::PlaylistManager;
+use crate::posters::PosterUploader;
+use crate::subtitles::SubtitleUploader;
+use crate::upload_target::{EncodingStatus, UploadedMedia, UploadTarget};
+
+const UPLOAD_CHUNK_SIZE: usize = 1024 * 1024; // 1 MB read from disk at a time
+/// Local files compared with the database when detecting the hash mode
+const HASH_MODE_SAMPLE_SIZE: usize = 20;
+
+pub struct MediaCmsTarget {
+    client: Client,
+    credentials: CredentialStore,
+    timeouts: TransferTimeouts,
+    bandwidth: Arc<BandwidthLimiter>,
+    chunked_uploader: Option<ChunkedUploader>,
+    /// Hashes of the media in MediaCMS, keyed by file size
+    file_metadata_from_db: HashMap<u64, Vec<String>>,
+    /// How MediaCMS made the hashes in its database, which do not say so themselves
+    hash_strategy: &'static dyn HashStrategy,
+    pool: Option<Pool<Postgres>>,
+    create_missing_categories: bool,
+    playlists: Option<PlaylistManager>,
+    subtitles: Option<SubtitleUploader>,
+    posters: Option<PosterUploader>,
+}
+
+impl MediaCmsTarget {
+    pub fn new(
+        client: Client,
+        credentials: CredentialStore,
+        config: &Config,
+        bandwidth: Arc<BandwidthLimiter>,
+        file_metadata_from_db: HashMap<u64, Vec<String>>,
+        hash_strategy: &'static dyn HashStrategy,
+        pool: Option<Pool<Postgres>>,
+    ) -> MediaCmsTarget {
+        MediaCmsTarget {
+            client,
+            credentials,
+            timeouts: TransferTimeouts::from_config(&config.http),
+            bandwidth,
+            chunked_uploader: config.chunked_upload.as_ref().map(ChunkedUploader::new),
+            file_metadata_from_db,
+            hash_strategy,
+            pool,
+            create_missing_categories: config.categories.create_missing,
+            playlists: config.playlists.as_ref().map(PlaylistManager::new),
+            subtitles: config.subtitles.as_ref().map(SubtitleUploader::new),
+            posters: config.posters.as_ref().map(PosterUploader::new),
+        }
+    }
+
+    /// Sends the whole file as one multipart request
+    async fn upload_direct(&self, data: &PathData, sent_bytes: &Arc<AtomicU64>) -> Result<UploadedMedia, UploadError> {
+        let url = env::var("API_URL").expect("API_URL must be set");
+
+        // Stream the file from disk in fixed size chunks so memory use does not grow with the file size
+        let file = File::open(&data.absolute_path).await?;
+        let activity = Activity::new();
+        let stream_activity = activity.clone();
+        let activity_at_end = activity.clone();
+        let bandwidth = self.bandwidth.clone();
+        let sent_bytes = sent_bytes.clone();
+        let stream = FramedRead::with_capacity(file, BytesCodec::new(), UPLOAD_CHUNK_SIZE)
+            .map_ok(BytesMut::freeze)
+            .and_then(move |bytes| {
+                let activity = stream_activity.clone();
+                let bandwidth = bandwidth.clone();
+                let sent_bytes = sent_bytes.clone();
+                async move {
+                    bandwidth.consume(bytes.len(), Some(&activity)).await;
+                    activity.touch();
+                    sent_bytes.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                    Ok(bytes)
+                }
+            })
+            .chain(stream::poll_fn(move |_| {
+                // The file has been read to the end, so the idle timeout no longer applies
+                activity_at_end.finish();
+                Poll::Ready(None)
+            }));
+
+        let body = Body::wrap_stream(stream);
+
+        let file_part = multipart::Part::stream_with_length(body, data.file_size)
+            .file_name(data.filename.clone())
+            .mime_str(&data.mime_type)?;
+
+        let form = multipart::Form::new()
+            .part("media_file", file_part)
+            .text("title", data.filename.clone());
+
+        let request = self.credentials
+            .get(&data.username)
+            .apply(self.client.post(url))
+            .timeout(self.timeouts.for_size(data.file_size))
+            .multipart(form)
+            .send();
+        let response = send_with_idle_timeout(request, &activity, self.timeouts.idle).await?;
+
+        if !response.status().is_success() {
+            return Err(UploadError::Rejected(response.status().as_u16()));
+        }
+        let body: Value = response.json().await?;
+        body["friendly_token"]
+            .as_str()
+            .map(|token| UploadedMedia { id: token.to_string() })
+            .ok_or(UploadError::MissingMedia)
+    }
+
+    /// Tags and categories can not be set through the API, so they go straight into the database.
+    /// Without a database, as with `--dry`, the media is left without them.
+    async fn set_tags_and_categories(&self, data: &PathData, media: &UploadedMedia) -> Result<(), UploadError> {
+        if let Some(pool) = &self.pool {
+            db::set_media_tags(pool, &media.id, &data.tags).await?;
+            db::set_media_categories(pool, &media.id, &data.categories, self.create_missing_categories).await?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UploadTarget for MediaCmsTarget {
+    fn media_count(&self) -> usize {
+        self.file_metadata_from_db.values().len()
+    }
+
+    fn hash_strategies(&self, _username: &str, file_size: u64) -> Vec<&'static dyn HashStrategy> {
+        if self.file_metadata_from_db.contains_key(&file_size) {
+            vec![self.hash_strategy]
+        } else {
+            vec![]
+        }
+    }
+
+    fn contains(&self, _username: &str, file_size: u64, hash: &StoredHash) -> bool {
+        hash.scheme == self.hash_strategy.id()
+            && self.file_metadata_from_db
+                .get(&file_size)
+                .is_some_and(|hashes| hashes.contains(&hash.value))
+    }
+
+    async fn upload(&self, data: &PathData, sent_bytes: &Arc<AtomicU64>) -> Result<UploadedMedia, UploadError> {
+        match &self.chunked_uploader {
+            Some(uploader) => {
+                let credentials = self.credentials.get(&data.username);
+                let token = uploader
+                    .upload(data, &self.client, credentials, &self.timeouts, &self.bandwidth, sent_bytes)
+                    .await?;
+                Ok(UploadedMedia { id: token })
+            }
+            None => self.upload_direct(data, sent_bytes).await,
+        }
+    }
+
+    fn follow_up_steps(&self, _username: &str) -> Vec<FollowUpStep> {
+        let mut steps = vec![];
+        // The fine-uploader endpoint does not take a title, and tags and categories are never sent
+        // with the upload
+        if self.chunked_uploader.is_some() || self.pool.is_some() {
+            steps.push(FollowUpStep::Metadata);
+        }
+        if self.playlists.is_some() {
+            steps.push(FollowUpStep::Playlist);
+        }
+        if self.subtitles.is_some() {
+            steps.push(FollowUpStep::Subtitles);
+        }
+        if self.posters.is_some() {
+            steps.push(FollowUpStep::Poster);
+        }
+        steps
+    }
+
+    async fn follow_up(&self, data: &PathData, media: &UploadedMedia, step: FollowUpStep) -> Result<(), UploadError> {
+        let credentials = self.credentials.get(&data.username);
+        match step {
+            FollowUpStep::Metadata => self.update_metadata(data, media).await,
+            FollowUpStep::Playlist => match &self.playlists {
+                Some(playlists) => playlists.add(&self.client, credentials, data, &media.id).await,
+                None => Ok(()),
+            },
+            FollowUpStep::Subtitles => match (&self.subtitles, &self.pool) {
+                (Some(subtitles), Some(pool)) => subtitles.attach(&self.client, credentials, pool, data, &media.id).await,
+                _ => Ok(()),
+            },
+            FollowUpStep::Poster => match &self.posters {
+                Some(posters) => posters.attach(&self.client, credentials, self.pool.as_ref(), data, &media.id).await,
+                None => Ok(()),
+            },
+        }
+    }
+
+    async fn update_metadata(&self, data: &PathData, media: &UploadedMedia) -> Result<(), UploadError> {
+        let url = media_url(media);
+
+        let form = multipart::Form::new()
+            .text("title", data.filename.clone());
+
+        let response = self.credentials
+            .get(&data.username)
+            .apply(self.client.put(url))
+            .multipart(form)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(UploadError::Rejected(response.status().as_u16()));
+        }
+        self.set_tags_and_categories(data, media).await
+    }
+
+    async fn encoding_status(&self, data: &PathData, media: &UploadedMedia) -> Result<EncodingStatus, UploadError> {
+        let response = self.credentials
+            .get(&data.username)
+            .apply(self.client.get(media_url(media)))
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(UploadError::Rejected(response.status().as_u16()));
+        }
+        let body: Value = response.json().await?;
+        Ok(match body["encoding_status"].as_str() {
+            Some("success") => EncodingStatus::Success,
+            Some("fail") => EncodingStatus::Failed,
+            _ => EncodingStatus::Pending,
+        })
+    }
+
+    async fn delete(&self, data: &PathData, media: &UploadedMedia) -> Result<(), UploadError> {
+        let response = self.credentials
+            .get(&data.username)
+            .apply(self.client.delete(media_url(media)))
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(UploadError::Rejected(response.status().as_u16()))
+        }
+    }
+}
+
+/// Finds how the hashes in the database were made, by hashing local files that have the size of
+/// existing media both ways. Returns `None` if none of them match either way.
+pub async fn detect_hash_mode(
+    file_metadata_from_db: &HashMap<u64, Vec<String>>,
+    files: &[(PathBuf, u64)],
+    hash_cache: &Arc<HashCache>,
+) -> Option<HashMode> {
+    let mut candidates: Vec<(u64, &PathBuf)> = files
+        .iter()
+        .filter(|(_, size)| file_metadata_from_db.contains_key(size))
+        .map(|(path, size)| (*size, path))
+        .collect();
+    // Small files first, as they are the quickest to hash in full
+    candidates.sort();
+
+    let mut partial_matches = 0;
+    let mut full_matches = 0;
+    for (size, path) in candidates.into_iter().take(HASH_MODE_SAMPLE_SIZE) {
+        let hashes = &file_metadata_from_db[&size];
+        let matches = |hash: io::Result<String>| hash.is_ok_and(|hash| hashes.contains(&hash));
+        if matches(hash_cache.get_or_compute_async(path, PARTIAL_MD5).await) {
+            partial_matches += 1;
+        } else if matches(hash_cache.get_or_compute_async(path, FULL_MD5).await) {
+            full_matches += 1;
+        }
+    }
+
+    match (partial_matches, full_matches) {
+        (0, 0) => None,
+        (partial, full) if full > partial => Some(HashMode::Full),
+        _ => Some(HashMode::Partial),
+    }
+}
+
+/// API URL of a single media
+fn media_url(media: &UploadedMedia) -> String {
+    let url = env::var("API_URL").expect("API_URL must be set");
+    format!("{}/{}", url.trim_end_matches('/'), media.id)
+}
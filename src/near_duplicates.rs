@@ -0,0 +1,344 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+use serde::{Deserialize, Serialize};
+use tokio::task;
+use crate::config::NearDuplicateConfig;
+
+/// Width and height of the grayscale thumbnail a frame hash is made from. Each row has one pixel more
+/// than the hash has bits, as every bit compares two neighbours.
+const HASH_WIDTH: usize = 9;
+const HASH_HEIGHT: usize = 8;
+/// Videos whose lengths differ more than this can not be copies of each other, even when trimmed
+const MIN_DURATION_RATIO: f64 = 0.5;
+/// The index is written to disk after this many new fingerprints
+const SAVE_INTERVAL: usize = 100;
+/// Frames with fewer or more set bits than this are nearly flat, like black frames, and match anything
+const MIN_FRAME_DETAIL: u32 = 4;
+
+/// Perceptual hashes of frames spread over the video, which survive re-encoding and scaling
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Fingerprint {
+    duration_secs: f64,
+    frames: Vec<u64>,
+}
+
+impl Fingerprint {
+    /// Whether most frames of this video have a close frame in the other one
+    fn matches(&self, other: &Fingerprint, max_distance: u32) -> bool {
+        let (shorter, longer) = if self.duration_secs < other.duration_secs {
+            (self.duration_secs, other.duration_secs)
+        } else {
+            (other.duration_secs, self.duration_secs)
+        };
+        if longer <= 0.0 || shorter / longer < MIN_DURATION_RATIO {
+            return false;
+        }
+
+        let frames: Vec<u64> = self.frames.iter().copied().filter(|frame| has_detail(*frame)).collect();
+        if frames.len() < 2 {
+            return false;
+        }
+        let close_frames = frames
+            .iter()
+            .filter(|frame| {
+                other.frames
+                    .iter()
+                    .any(|other_frame| has_detail(*other_frame) && (**frame ^ other_frame).count_ones() <= max_distance)
+            })
+            .count();
+        close_frames * 2 >= frames.len()
+    }
+}
+
+fn has_detail(frame: u64) -> bool {
+    (MIN_FRAME_DETAIL..=64 - MIN_FRAME_DETAIL).contains(&frame.count_ones())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct IndexEntry {
+    /// The fingerprint is made again if the size or modification time changes
+    file_size: u64,
+    #[serde(default)]
+    modified_secs: i64,
+    fingerprint: Fingerprint,
+    /// Whether the file is in the target, so other files are compared against it
+    uploaded: bool,
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct NearDuplicate {
+    path: String,
+    /// The file that is already in the target
+    matches: String,
+    skipped: bool,
+}
+
+struct Index {
+    entries: HashMap<String, IndexEntry>,
+    unsaved_changes: usize,
+}
+
+/// Finds re-encoded and trimmed copies of uploaded videos, which have other hashes than the original
+pub struct NearDuplicateDetector {
+    config: NearDuplicateConfig,
+    index: Mutex<Index>,
+    found: Mutex<Vec<NearDuplicate>>,
+}
+
+impl NearDuplicateDetector {
+    pub fn load(config: &NearDuplicateConfig) -> NearDuplicateDetector {
+        let entries = fs::read_to_string(&config.index_file)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        NearDuplicateDetector {
+            config: config.clone(),
+            index: Mutex::new(Index { entries, unsaved_changes: 0 }),
+            found: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn skips_matches(&self) -> bool {
+        self.config.skip
+    }
+
+    /// Returns the uploaded file this one likely is a copy of. The file is added to the index, but
+    /// only counts as uploaded once `mark_uploaded` is called for it.
+    pub async fn check(&self, path: &str, file_size: u64) -> Option<String> {
+        let modified_secs = modified_secs(path)?;
+        let fingerprint = self.fingerprint(path, file_size, modified_secs).await?;
+        let mut index = self.index.lock().unwrap();
+        let original = index
+            .entries
+            .iter()
+            .filter(|(other_path, entry)| entry.uploaded && other_path.as_str() != path)
+            .find(|(_, entry)| fingerprint.matches(&entry.fingerprint, self.config.max_distance))
+            .map(|(other_path, _)| other_path.clone());
+
+        let skipped = original.is_some() && self.config.skip;
+        index.entries.insert(path.to_string(), IndexEntry {
+            file_size,
+            modified_secs,
+            fingerprint,
+            uploaded: false,
+        });
+        self.changed(&mut index);
+        drop(index);
+
+        if let Some(original) = &original {
+            let mut found = self.found.lock().unwrap();
+            found.push(NearDuplicate {
+                path: path.to_string(),
+                matches: original.clone(),
+                skipped,
+            });
+            if let Ok(serialized) = serde_json::to_string_pretty(&*found) {
+                let _ = fs::write(&self.config.report_file, serialized);
+            }
+        }
+        original
+    }
+
+    /// Adds a file that is already in the target to the index, if configured
+    pub async fn add_existing(&self, path: &str, file_size: u64) {
+        if !self.config.index_existing {
+            return;
+        }
+        let Some(modified_secs) = modified_secs(path) else {
+            return;
+        };
+        let Some(fingerprint) = self.fingerprint(path, file_size, modified_secs).await else {
+            return;
+        };
+        let mut index = self.index.lock().unwrap();
+        index.entries.insert(path.to_string(), IndexEntry {
+            file_size,
+            modified_secs,
+            fingerprint,
+            uploaded: true,
+        });
+        self.changed(&mut index);
+    }
+
+    /// Compares later files against this one, now that its upload went through
+    pub fn mark_uploaded(&self, path: &str) {
+        let mut index = self.index.lock().unwrap();
+        if let Some(entry) = index.entries.get_mut(path) {
+            if !entry.uploaded {
+                entry.uploaded = true;
+                self.changed(&mut index);
+            }
+        }
+    }
+
+    /// Drops files that are no longer in the library
+    pub fn retain(&self, paths: &[PathBuf]) {
+        let existing: HashSet<String> = paths
+            .iter()
+            .map(|path| path.to_string_lossy().to_string())
+            .collect();
+        let mut index = self.index.lock().unwrap();
+        let count = index.entries.len();
+        index.entries.retain(|path, _| existing.contains(path));
+        index.unsaved_changes += count - index.entries.len();
+    }
+
+    /// The fingerprint from the index, or a new one if the file is not in it or has changed
+    async fn fingerprint(&self, path: &str, file_size: u64, modified_secs: i64) -> Option<Fingerprint> {
+        let cached = self.index
+            .lock()
+            .unwrap()
+            .entries
+            .get(path)
+            .filter(|entry| entry.file_size == file_size && entry.modified_secs == modified_secs)
+            .map(|entry| entry.fingerprint.clone());
+        if cached.is_some() {
+            return cached;
+        }
+        // ffprobe and ffmpeg are waited for, so they run on a blocking thread
+        let path = PathBuf::from(path);
+        let frames = self.config.frames;
+        task::spawn_blocking(move || compute_fingerprint(&path, frames)).await.ok().flatten()
+    }
+
+    fn changed(&self, index: &mut Index) {
+        index.unsaved_changes += 1;
+        if index.unsaved_changes >= SAVE_INTERVAL {
+            self.save(index);
+        }
+    }
+
+    /// Writes the index to disk if anything changed since it was last written
+    pub fn flush(&self) {
+        let mut index = self.index.lock().unwrap();
+        if index.unsaved_changes > 0 {
+            self.save(&mut index);
+        }
+    }
+
+    fn save(&self, index: &mut Index) {
+        let temporary_path = format!("{}.tmp", self.config.index_file);
+        if let Ok(serialized) = serde_json::to_string(&index.entries) {
+            if fs::write(&temporary_path, serialized).is_ok() && fs::rename(&temporary_path, &self.config.index_file).is_ok() {
+                index.unsaved_changes = 0;
+            }
+        }
+    }
+}
+
+impl Drop for NearDuplicateDetector {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+fn modified_secs(path: &str) -> Option<i64> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(match modified.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch.as_secs() as i64,
+        Err(error) => -(error.duration().as_secs() as i64),
+    })
+}
+
+/// Hashes frames spread evenly over the video, leaving out the very start and end where intros,
+/// credits and trimming differ most
+fn compute_fingerprint(path: &Path, frame_count: usize) -> Option<Fingerprint> {
+    let duration_secs = video_duration(path)?;
+    let frames: Vec<u64> = (1..=frame_count)
+        .filter_map(|index| frame_hash(path, duration_secs * index as f64 / (frame_count + 1) as f64))
+        .collect();
+    if frames.is_empty() {
+        return None;
+    }
+    Some(Fingerprint { duration_secs, frames })
+}
+
+fn video_duration(path: &Path) -> Option<f64> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-show_entries", "format=duration", "-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(path)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+/// Difference hash of the keyframe at or just before the position, from a tiny grayscale thumbnail
+fn frame_hash(path: &Path, position_secs: f64) -> Option<u64> {
+    let output = Command::new("ffmpeg")
+        .args(["-v", "error", "-skip_frame", "nokey", "-ss", &format!("{:.3}", position_secs), "-i"])
+        .arg(path)
+        .args(["-frames:v", "1", "-vf", &format!("scale={}:{},format=gray", HASH_WIDTH, HASH_HEIGHT), "-f", "rawvideo", "-"])
+        .output()
+        .ok()?;
+    if !output.status.success() || output.stdout.len() != HASH_WIDTH * HASH_HEIGHT {
+        return None;
+    }
+
+    let pixels = output.stdout;
+    let mut hash = 0u64;
+    for row in 0..HASH_HEIGHT {
+        for column in 0..HASH_WIDTH - 1 {
+            let index = row * HASH_WIDTH + column;
+            hash = (hash << 1) | u64::from(pixels[index] < pixels[index + 1]);
+        }
+    }
+    Some(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frames with half of their bits set, each different from the others
+    const FRAMES: [u64; 4] = [
+        0x0000_0000_FFFF_FFFF,
+        0x00FF_00FF_00FF_00FF,
+        0x0F0F_0F0F_0F0F_0F0F,
+        0x3333_3333_3333_3333,
+    ];
+
+    fn fingerprint(duration_secs: f64, frames: &[u64]) -> Fingerprint {
+        Fingerprint { duration_secs, frames: frames.to_vec() }
+    }
+
+    #[test]
+    fn re_encoded_copy_matches() {
+        let original = fingerprint(60.0, &FRAMES);
+        // A few bits flipped in every frame, as re-encoding does
+        let copy = fingerprint(59.5, &FRAMES.map(|frame| frame ^ 0b101));
+        assert!(original.matches(&copy, 2));
+        assert!(copy.matches(&original, 2));
+        assert!(!original.matches(&copy, 1));
+    }
+
+    #[test]
+    fn lengths_far_apart_do_not_match() {
+        assert!(!fingerprint(60.0, &FRAMES).matches(&fingerprint(29.0, &FRAMES), 0));
+        assert!(fingerprint(60.0, &FRAMES).matches(&fingerprint(30.0, &FRAMES), 0));
+        assert!(!fingerprint(0.0, &FRAMES).matches(&fingerprint(0.0, &FRAMES), 0));
+    }
+
+    #[test]
+    fn half_of_the_frames_must_be_close() {
+        let original = fingerprint(60.0, &FRAMES);
+        assert!(original.matches(&fingerprint(60.0, &FRAMES[..2]), 0));
+        assert!(!original.matches(&fingerprint(60.0, &FRAMES[..1]), 0));
+    }
+
+    #[test]
+    fn flat_frames_are_ignored() {
+        let black = [0, 0, 1, u64::MAX];
+        assert!(!fingerprint(60.0, &black).matches(&fingerprint(60.0, &black), 64));
+
+        // Flat frames in the other video do not count as close either
+        let original = fingerprint(60.0, &[FRAMES[0], FRAMES[1]]);
+        assert!(!original.matches(&fingerprint(60.0, &black), 64));
+    }
+}
@@ -1,9 +1,7 @@
-use std::env;
-use std::ops::Add;
-use reqwest::{Body, Client, Error, multipart, Response};
-use std::sync::Arc;
-use futures::{stream};
-use tokio_util::bytes::Bytes;
+use std::io;
+use std::path::Path;
+use std::fmt::{Display, Formatter};
+use crate::file_extension::FileExtension;
 
 #[derive(Clone)]
 pub struct PathData {
@@ -12,47 +10,78 @@ pub struct PathData {
     pub filename: String,
     pub(crate) username: String,
     pub tags: Vec<String>,
+    pub categories: Vec<String>,
     pub mime_type: String,
-    pub file_buffer: Arc<Vec<u8>>,
+    /// The container found by extension and by content, if they differ
+    pub extension_mismatch: Option<(FileExtension, FileExtension)>,
+    pub file_size: u64,
 }
 
-impl<'a> PathData {
-    pub async fn upload(&self, client: &Client) -> Result<Response, Error> {
-        let url = env::var("API_URL").expect("API_URL must be set");
-
-        let buffer_clone = Arc::clone(&self.file_buffer).to_vec();
-        let stream = stream::once(async move {
-            Ok::<Bytes, std::io::Error>(Bytes::from(buffer_clone))
-        });
+impl PathData {
+    /// Takes the MIME type from the content, with the extension only as a fallback. Only done for
+    /// files that are uploaded, as it reads from the file.
+    pub fn sniff_container(&mut self) {
+        let path = Path::new(&self.absolute_path);
+        let extension = FileExtension::from(path);
+        let container = match FileExtension::sniff(path) {
+            Ok(FileExtension::Unknown) | Err(_) => extension,
+            Ok(container) => container,
+        };
+        self.extension_mismatch = (container != extension).then_some((extension, container));
+        self.mime_type = container.mime_type().to_string();
+    }
+}
 
-        let body = Body::wrap_stream(stream);
+#[derive(Debug)]
+pub enum UploadError {
+    Io(io::Error),
+    Request(reqwest::Error),
+    /// The server answered with a status that is not a success
+    Rejected(u16),
+    /// Writing to the database of the target failed after the upload itself went through
+    Database(sqlx::Error),
+    MissingMedia,
+    Stalled,
+}
 
-        let file_part = match multipart::Part::stream(body)
-            .file_name(self.filename.clone())
-            .mime_str("video/mp4") {
-            Ok(part) => part,
-            Err(ref e) => {
-                println!("Error creating file part: {:?}", e);
-                panic!()
-            }
-        };
+impl UploadError {
+    /// HTTP status code of the failure, or 0 if the request never got a response
+    pub fn status_code(&self) -> u16 {
+        match self {
+            UploadError::Io(_) | UploadError::Database(_) | UploadError::MissingMedia | UploadError::Stalled => 0,
+            UploadError::Request(error) => error.status().map(|status| status.as_u16()).unwrap_or(0),
+            UploadError::Rejected(status_code) => *status_code,
+        }
+    }
+}
 
-        let description = self.tags.join(",");
+impl Display for UploadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadError::Io(error) => write!(f, "{}", error),
+            UploadError::Request(error) => write!(f, "{}", error),
+            UploadError::Rejected(status_code) => write!(f, "Server responded with status {}", status_code),
+            UploadError::Database(error) => write!(f, "{}", error),
+            UploadError::MissingMedia => write!(f, "Server did not return the uploaded media"),
+            UploadError::Stalled => write!(f, "Upload made no progress within the read timeout"),
+        }
+    }
+}
 
-        let form = multipart::Form::new()
-            .part("media_file", file_part)
-            .text("title", self.filename.clone())
-            .text("description", description);
+impl From<io::Error> for UploadError {
+    fn from(error: io::Error) -> Self {
+        UploadError::Io(error)
+    }
+}
 
-        let password = &self.username.to_uppercase().add("_PASSWORD");
-        let password = env::var(password)
-            .expect("Password not in env file");
+impl From<sqlx::Error> for UploadError {
+    fn from(error: sqlx::Error) -> Self {
+        UploadError::Database(error)
+    }
+}
 
-        client
-            .post(url)
-            .basic_auth(&self.username, Some(password))
-            .multipart(form)
-            .send()
-            .await
+impl From<reqwest::Error> for UploadError {
+    fn from(error: reqwest::Error) -> Self {
+        UploadError::Request(error)
     }
 }
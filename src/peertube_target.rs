@@ -0,0 +1,573 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, LOCATION, RANGE};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::time::Instant;
+use crate::api::TransferTimeouts;
+use crate::bandwidth::BandwidthLimiter;
+use crate::config::{Config, PeerTubeConfig, PeerTubePrivacy};
+use crate::follow_ups::FollowUpStep;
+use crate::hash_cache::{FileStamp, HashCache};
+use crate::hash_strategy::{HashStrategy, StoredHash};
+use crate::path_data::{PathData, UploadError};
+use crate::secrets::{create_secret_provider, Secret};
+use crate::upload_target::{EncodingStatus, strategies_of, UploadedMedia, UploadTarget};
+
+/// Access tokens are refreshed this long before they expire, so a chunk is never sent with a stale one
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+/// PeerTube rejects videos with more tags, or tags outside of this length
+const MAX_TAGS: usize = 5;
+const TAG_LENGTH: std::ops::RangeInclusive<usize> = 2..=30;
+const MAX_NAME_LENGTH: usize = 120;
+const VIDEO_STATE_PUBLISHED: u64 = 1;
+/// Transcoding failed, and moving to object storage or the file system failed
+const VIDEO_STATES_FAILED: [u64; 3] = [7, 8, 11];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ResumableUpload {
+    /// Upload URL handed out by PeerTube when the upload was started
+    location: String,
+    file_size: u64,
+    /// The file as it was when the upload was started. Missing in state from earlier versions.
+    #[serde(default)]
+    stamp: Option<FileStamp>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct UploadedVideo {
+    uuid: String,
+    username: String,
+    file_size: u64,
+    hash: StoredHash,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct PeerTubeState {
+    /// Unfinished resumable uploads, keyed by absolute path
+    uploads: HashMap<String, ResumableUpload>,
+    /// Every file uploaded to the instance by this program
+    videos: Vec<UploadedVideo>,
+}
+
+/// PeerTube state mirrored to disk after every change, so uploads resume and duplicates are known
+/// after a restart
+struct PeerTubeStateStore {
+    file_path: String,
+    state: Mutex<PeerTubeState>,
+}
+
+impl PeerTubeStateStore {
+    fn load(file_path: &str) -> PeerTubeStateStore {
+        let state = fs::read_to_string(file_path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        PeerTubeStateStore {
+            file_path: file_path.to_string(),
+            state: Mutex::new(state),
+        }
+    }
+
+    fn upload(&self, path: &str) -> Option<ResumableUpload> {
+        self.state.lock().unwrap().uploads.get(path).cloned()
+    }
+
+    fn set_upload(&self, path: &str, upload: ResumableUpload) {
+        let mut state = self.state.lock().unwrap();
+        state.uploads.insert(path.to_string(), upload);
+        self.save(&state);
+    }
+
+    fn remove_upload(&self, path: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.uploads.remove(path).is_some() {
+            self.save(&state);
+        }
+    }
+
+    fn finish(&self, path: &str, video: UploadedVideo) {
+        let mut state = self.state.lock().unwrap();
+        state.uploads.remove(path);
+        state.videos.push(video);
+        self.save(&state);
+    }
+
+    fn remove_video(&self, uuid: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.videos.retain(|video| video.uuid != uuid);
+        self.save(&state);
+    }
+
+    fn save(&self, state: &PeerTubeState) {
+        if let Ok(serialized) = serde_json::to_string_pretty(state) {
+            let _ = fs::write(&self.file_path, serialized);
+        }
+    }
+}
+
+struct Session {
+    access_token: Secret,
+    refresh_token: Secret,
+    expires_at: Instant,
+}
+
+struct Account {
+    /// Kept to log in again if the refresh token has expired as well
+    password: Secret,
+    channel_id: u64,
+    session: tokio::sync::Mutex<Session>,
+}
+
+#[derive(Deserialize)]
+struct OAuthClient {
+    client_id: String,
+    client_secret: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: String,
+    expires_in: u64,
+}
+
+/// Uploads to PeerTube through its resumable upload API, logged in with OAuth as the user the file
+/// belongs to
+pub struct PeerTubeTarget {
+    client: Client,
+    url: String,
+    privacy: u8,
+    chunk_size: u64,
+    timeouts: TransferTimeouts,
+    bandwidth: Arc<BandwidthLimiter>,
+    oauth_client: OAuthClient,
+    accounts: HashMap<String, Account>,
+    state: PeerTubeStateStore,
+    hash_cache: Arc<HashCache>,
+    hash_strategy: &'static dyn HashStrategy,
+}
+
+impl PeerTubeTarget {
+    /// Logs in every user and finds the channel their videos go to
+    pub async fn connect(
+        client: Client,
+        peertube: &PeerTubeConfig,
+        config: &Config,
+        usernames: &[String],
+        hash_cache: Arc<HashCache>,
+        bandwidth: Arc<BandwidthLimiter>,
+    ) -> Result<PeerTubeTarget, String> {
+        let url = peertube.url.trim_end_matches('/').to_string();
+        let oauth_client = client
+            .get(format!("{}/api/v1/oauth-clients/local", url))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|error| format!("Could not get the OAuth client of {}: {}", url, error))?
+            .json::<OAuthClient>()
+            .await
+            .map_err(|error| format!("Invalid OAuth client from {}: {}", url, error))?;
+
+        let mut target = PeerTubeTarget {
+            client,
+            url,
+            privacy: match peertube.privacy {
+                PeerTubePrivacy::Public => 1,
+                PeerTubePrivacy::Unlisted => 2,
+                PeerTubePrivacy::Private => 3,
+                PeerTubePrivacy::Internal => 4,
+            },
+            chunk_size: peertube.chunk_size_mb.max(1) * 1024 * 1024,
+            timeouts: TransferTimeouts::from_config(&config.http),
+            bandwidth,
+            oauth_client,
+            accounts: HashMap::new(),
+            state: PeerTubeStateStore::load(&peertube.state_file),
+            hash_cache,
+            hash_strategy: config.hash_scheme.strategy(),
+        };
+
+        let secrets = create_secret_provider(&config.auth.secret_source);
+        for username in usernames {
+            let password = secrets
+                .get(&format!("{}_PASSWORD", username.to_uppercase()))
+                .map_err(|error| format!("No secret for user {}: {}", username, error))?;
+            let session = target
+                .login(username, &password)
+                .await
+                .map_err(|error| format!("PeerTube login failed for {}: {}", username, error))?;
+            let channel_id = target
+                .default_channel(&session)
+                .await
+                .map_err(|error| format!("Could not find a channel for {}: {}", username, error))?;
+            target.accounts.insert(username.clone(), Account {
+                password,
+                channel_id,
+                session: tokio::sync::Mutex::new(session),
+            });
+        }
+        Ok(target)
+    }
+
+    async fn login(&self, username: &str, password: &Secret) -> Result<Session, UploadError> {
+        self.request_token(&[
+            ("grant_type", "password"),
+            ("username", username),
+            ("password", password.expose()),
+        ]).await
+    }
+
+    async fn request_token(&self, grant: &[(&str, &str)]) -> Result<Session, UploadError> {
+        let mut form = vec![
+            ("client_id", self.oauth_client.client_id.as_str()),
+            ("client_secret", self.oauth_client.client_secret.as_str()),
+        ];
+        form.extend_from_slice(grant);
+
+        let response = self.client
+            .post(format!("{}/api/v1/users/token", self.url))
+            .form(&form)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(UploadError::Rejected(response.status().as_u16()));
+        }
+        let token: TokenResponse = response.json().await?;
+        Ok(Session {
+            access_token: Secret::new(token.access_token),
+            refresh_token: Secret::new(token.refresh_token),
+            expires_at: Instant::now() + Duration::from_secs(token.expires_in),
+        })
+    }
+
+    async fn default_channel(&self, session: &Session) -> Result<u64, UploadError> {
+        let response = self.client
+            .get(format!("{}/api/v1/users/me", self.url))
+            .bearer_auth(session.access_token.expose())
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(UploadError::Rejected(response.status().as_u16()));
+        }
+        let body: Value = response.json().await?;
+        body["videoChannels"][0]["id"].as_u64().ok_or(UploadError::MissingMedia)
+    }
+
+    /// A valid access token for the user, refreshing it or logging in again when it is about to expire
+    async fn access_token(&self, username: &str) -> Result<String, UploadError> {
+        // Every user that read_file can produce was logged in at startup
+        let account = &self.accounts[username];
+        let mut session = account.session.lock().await;
+        if session.expires_at <= Instant::now() + TOKEN_REFRESH_MARGIN {
+            let refresh_token = session.refresh_token.expose().to_string();
+            let refreshed = self.request_token(&[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh_token.as_str()),
+            ]).await;
+            *session = match refreshed {
+                Ok(refreshed) => refreshed,
+                Err(_) => self.login(username, &account.password).await?,
+            };
+        }
+        Ok(session.access_token.expose().to_string())
+    }
+
+    /// Creates the video and returns the URL its content is sent to
+    async fn start_upload(&self, data: &PathData, stamp: FileStamp, access_token: &str) -> Result<String, UploadError> {
+        let body = json!({
+            "name": video_name(&data.filename),
+            "channelId": self.accounts[&data.username].channel_id,
+            "filename": data.filename,
+            "privacy": self.privacy,
+            "tags": video_tags(&data.tags),
+        });
+
+        let response = self.client
+            .post(format!("{}/api/v1/videos/upload-resumable", self.url))
+            .bearer_auth(access_token)
+            .header("X-Upload-Content-Length", data.file_size)
+            .header("X-Upload-Content-Type", &data.mime_type)
+            .json(&body)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(UploadError::Rejected(response.status().as_u16()));
+        }
+
+        let location = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|value| value.to_str().ok())
+            .map(|location| self.absolute_url(location))
+            .ok_or(UploadError::MissingMedia)?;
+        self.state.set_upload(&data.absolute_path, ResumableUpload {
+            location: location.clone(),
+            file_size: data.file_size,
+            stamp: Some(stamp),
+        });
+        Ok(location)
+    }
+
+    /// Asks PeerTube how much of an earlier upload it has
+    async fn resume_state(&self, location: &str, access_token: &str, file_size: u64) -> Result<ResumeState, UploadError> {
+        let response = self.client
+            .put(location)
+            .bearer_auth(access_token)
+            .header(CONTENT_RANGE, format!("bytes */{}", file_size))
+            .header(CONTENT_LENGTH, 0)
+            .send()
+            .await?;
+        if response.status().is_success() {
+            // The last chunk arrived, but the run ended before the answer was stored
+            let body: Value = response.json().await?;
+            return video_uuid(&body).map(ResumeState::Finished);
+        }
+        if response.status() != StatusCode::PERMANENT_REDIRECT {
+            return Ok(ResumeState::Unknown);
+        }
+        // `Range: bytes=0-[last byte received]`, missing if nothing was received yet
+        let offset = response
+            .headers()
+            .get(RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|range| range.rsplit('-').next())
+            .and_then(|last| last.parse::<u64>().ok())
+            .map(|last| last + 1)
+            .unwrap_or(0);
+        Ok(ResumeState::Offset(offset))
+    }
+
+    /// Remembers the video of a finished upload, so it is found as existing by later runs
+    fn finish_upload(&self, data: &PathData, uuid: String, hash: StoredHash) -> UploadedMedia {
+        self.state.finish(&data.absolute_path, UploadedVideo {
+            uuid: uuid.clone(),
+            username: data.username.clone(),
+            file_size: data.file_size,
+            hash,
+        });
+        UploadedMedia { id: uuid }
+    }
+
+    /// The Location header may leave out the scheme or the host
+    fn absolute_url(&self, location: &str) -> String {
+        if location.starts_with("//") {
+            let scheme = self.url.split("//").next().unwrap_or("https:");
+            format!("{}{}", scheme, location)
+        } else if location.starts_with('/') {
+            format!("{}{}", self.url, location)
+        } else {
+            location.to_string()
+        }
+    }
+}
+
+#[async_trait]
+impl UploadTarget for PeerTubeTarget {
+    fn media_count(&self) -> usize {
+        self.state.state.lock().unwrap().videos.len()
+    }
+
+    fn hash_strategies(&self, _username: &str, file_size: u64) -> Vec<&'static dyn HashStrategy> {
+        let hashes: Vec<StoredHash> = self.state.state
+            .lock()
+            .unwrap()
+            .videos
+            .iter()
+            .filter(|video| video.file_size == file_size)
+            .map(|video| video.hash.clone())
+            .collect();
+        strategies_of(hashes.iter())
+    }
+
+    fn contains(&self, _username: &str, file_size: u64, hash: &StoredHash) -> bool {
+        self.state.state.lock().unwrap().videos.iter().any(|video| {
+            video.file_size == file_size && &video.hash == hash
+        })
+    }
+
+    async fn upload(&self, data: &PathData, sent_bytes: &Arc<AtomicU64>) -> Result<UploadedMedia, UploadError> {
+        if data.file_size == 0 {
+            return Err(UploadError::Io(io::Error::new(io::ErrorKind::InvalidData, "PeerTube does not accept empty files")));
+        }
+        // Hashed before the upload, so a file that can not be read does not leave a video without a hash
+        let hash = self.hash_cache
+            .get_or_compute_async(Path::new(&data.absolute_path), self.hash_strategy)
+            .await?;
+        let hash = StoredHash::new(self.hash_strategy, hash);
+        let access_token = self.access_token(&data.username).await?;
+
+        let stamp = FileStamp::read(Path::new(&data.absolute_path))?;
+        let earlier_upload = self.state
+            .upload(&data.absolute_path)
+            // Only resume if the file is unchanged since the upload was started
+            .filter(|upload| upload.stamp.as_ref() == Some(&stamp));
+        let resumed = match earlier_upload {
+            Some(upload) => match self.resume_state(&upload.location, &access_token, data.file_size).await? {
+                ResumeState::Offset(offset) => Some((upload.location, offset)),
+                ResumeState::Finished(uuid) => return Ok(self.finish_upload(data, uuid, hash)),
+                ResumeState::Unknown => None,
+            },
+            None => None,
+        };
+        let (location, mut offset) = match resumed {
+            Some(resumed) => resumed,
+            None => (self.start_upload(data, stamp, &access_token).await?, 0),
+        };
+
+        sent_bytes.store(offset, Ordering::Relaxed);
+
+        let mut file = File::open(&data.absolute_path).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+
+        loop {
+            // PeerTube keeps the chunks that were sent, so a pause can start between two of them
+            self.bandwidth.wait_until_unpaused().await;
+            let length = self.chunk_size.min(data.file_size - offset);
+            let mut chunk = vec![0; length as usize];
+            file.read_exact(&mut chunk).await?;
+
+            self.bandwidth.consume(chunk.len(), None).await;
+
+            // Long uploads can outlive the access token
+            let access_token = self.access_token(&data.username).await?;
+            let response = self.client
+                .put(&location)
+                .bearer_auth(access_token)
+                .header(CONTENT_TYPE, "application/octet-stream")
+                .header(CONTENT_RANGE, format!("bytes {}-{}/{}", offset, offset + length - 1, data.file_size))
+                .timeout(self.timeouts.for_size(length))
+                .body(chunk)
+                .send()
+                .await?;
+
+            match response.status() {
+                StatusCode::PERMANENT_REDIRECT => {
+                    offset += length;
+                    sent_bytes.fetch_add(length, Ordering::Relaxed);
+                    if offset >= data.file_size {
+                        return Err(UploadError::MissingMedia);
+                    }
+                }
+                status if status.is_success() => {
+                    sent_bytes.fetch_add(length, Ordering::Relaxed);
+                    let body: Value = response.json().await?;
+                    return Ok(self.finish_upload(data, video_uuid(&body)?, hash));
+                }
+                status => {
+                    if status == StatusCode::NOT_FOUND {
+                        // The upload expired on the server, so start over on the next attempt
+                        self.state.remove_upload(&data.absolute_path);
+                    }
+                    return Err(UploadError::Rejected(status.as_u16()));
+                }
+            }
+        }
+    }
+
+    fn follow_up_steps(&self, _username: &str) -> Vec<FollowUpStep> {
+        // Name and tags are sent when the upload is started
+        vec![]
+    }
+
+    async fn follow_up(&self, _data: &PathData, _media: &UploadedMedia, _step: FollowUpStep) -> Result<(), UploadError> {
+        Ok(())
+    }
+
+    async fn update_metadata(&self, data: &PathData, media: &UploadedMedia) -> Result<(), UploadError> {
+        let access_token = self.access_token(&data.username).await?;
+        let response = self.client
+            .put(format!("{}/api/v1/videos/{}", self.url, media.id))
+            .bearer_auth(access_token)
+            .json(&json!({
+                "name": video_name(&data.filename),
+                "tags": video_tags(&data.tags),
+            }))
+            .send()
+            .await?;
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(UploadError::Rejected(response.status().as_u16()))
+        }
+    }
+
+    async fn encoding_status(&self, data: &PathData, media: &UploadedMedia) -> Result<EncodingStatus, UploadError> {
+        let access_token = self.access_token(&data.username).await?;
+        let response = self.client
+            .get(format!("{}/api/v1/videos/{}", self.url, media.id))
+            .bearer_auth(access_token)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(UploadError::Rejected(response.status().as_u16()));
+        }
+        let body: Value = response.json().await?;
+        Ok(match body["state"]["id"].as_u64() {
+            Some(VIDEO_STATE_PUBLISHED) => EncodingStatus::Success,
+            Some(state) if VIDEO_STATES_FAILED.contains(&state) => EncodingStatus::Failed,
+            _ => EncodingStatus::Pending,
+        })
+    }
+
+    async fn delete(&self, data: &PathData, media: &UploadedMedia) -> Result<(), UploadError> {
+        let access_token = self.access_token(&data.username).await?;
+        let response = self.client
+            .delete(format!("{}/api/v1/videos/{}", self.url, media.id))
+            .bearer_auth(access_token)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(UploadError::Rejected(response.status().as_u16()));
+        }
+        self.state.remove_video(&media.id);
+        Ok(())
+    }
+}
+
+/// What PeerTube knows about an upload that was started by an earlier attempt
+enum ResumeState {
+    /// Bytes it has received so far
+    Offset(u64),
+    /// Every byte arrived and the video was created
+    Finished(String),
+    /// The upload expired or never existed
+    Unknown,
+}
+
+fn video_uuid(body: &Value) -> Result<String, UploadError> {
+    body["video"]["uuid"]
+        .as_str()
+        .map(String::from)
+        .ok_or(UploadError::MissingMedia)
+}
+
+fn video_name(filename: &str) -> String {
+    filename.chars().take(MAX_NAME_LENGTH).collect()
+}
+
+/// The folder tags PeerTube accepts, in folder order
+fn video_tags(tags: &[String]) -> Vec<String> {
+    let mut accepted: Vec<String> = Vec::new();
+    for tag in tags {
+        if TAG_LENGTH.contains(&tag.chars().count()) && !accepted.contains(tag) {
+            accepted.push(tag.clone());
+        }
+    }
+    accepted.truncate(MAX_TAGS);
+    accepted
+}
@@ -0,0 +1,298 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use reqwest::{Client, multipart, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::api;
+use crate::auth::Credentials;
+use crate::config::PlaylistConfig;
+use crate::path_data::{PathData, UploadError};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct PlaylistEntry {
+    /// Path of the file below the playlist folder, which decides its place in the playlist
+    path: String,
+    media_token: String,
+    /// Position last sent to MediaCMS
+    ordering: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Playlist {
+    token: String,
+    username: String,
+    entries: Vec<PlaylistEntry>,
+}
+
+/// Playlists made by earlier runs, keyed by the folder they were made for, so reruns add to them
+/// instead of making new ones
+struct PlaylistStore {
+    file_path: String,
+    playlists: Mutex<HashMap<String, Playlist>>,
+}
+
+impl PlaylistStore {
+    fn load(file_path: &str) -> PlaylistStore {
+        let playlists = fs::read_to_string(file_path)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        PlaylistStore {
+            file_path: file_path.to_string(),
+            playlists: Mutex::new(playlists),
+        }
+    }
+
+    fn get(&self, folder: &str) -> Option<Playlist> {
+        self.playlists.lock().unwrap().get(folder).cloned()
+    }
+
+    fn set(&self, folder: &str, playlist: Playlist) {
+        let mut playlists = self.playlists.lock().unwrap();
+        playlists.insert(folder.to_string(), playlist);
+        if let Ok(serialized) = serde_json::to_string_pretty(&*playlists) {
+            let _ = fs::write(&self.file_path, serialized);
+        }
+    }
+}
+
+/// The folder of a file that gets a playlist
+struct PlaylistFolder {
+    /// Path of the folder below the root folder, including the user folder
+    key: String,
+    title: String,
+    /// Path of the file below the playlist folder
+    entry_path: String,
+}
+
+/// Puts uploaded media in a playlist per folder at the configured depth below the user folder,
+/// in natural filename order
+pub struct PlaylistManager {
+    depth: usize,
+    store: PlaylistStore,
+    /// Playlist changes are made one at a time, so concurrent uploads neither create the same
+    /// playlist twice nor mix up the ordering
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl PlaylistManager {
+    pub fn new(config: &PlaylistConfig) -> PlaylistManager {
+        PlaylistManager {
+            depth: config.depth.max(1),
+            store: PlaylistStore::load(&config.state_file),
+            lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Adds the media to the playlist of its folder, creating the playlist if needed
+    pub async fn add(&self, client: &Client, credentials: &Credentials, data: &PathData, media_token: &str) -> Result<(), UploadError> {
+        let Some(folder) = self.playlist_folder(&data.relative_path) else {
+            return Ok(());
+        };
+        let _guard = self.lock.lock().await;
+
+        let mut playlist = match self.store.get(&folder.key) {
+            Some(playlist) => playlist,
+            None => self.create(client, credentials, &folder, &data.username).await?,
+        };
+
+        if !add_media(client, credentials, &playlist.token, media_token).await? {
+            // The playlist was deleted in MediaCMS, so make it again with everything it had
+            let entries = playlist.entries;
+            playlist = self.create(client, credentials, &folder, &data.username).await?;
+            for entry in &entries {
+                add_media(client, credentials, &playlist.token, &entry.media_token).await?;
+            }
+            add_media(client, credentials, &playlist.token, media_token).await?;
+            playlist.entries = entries
+                .into_iter()
+                .map(|entry| PlaylistEntry { ordering: 0, ..entry })
+                .collect();
+        }
+
+        // A file that is uploaded again replaces its earlier media
+        playlist.entries.retain(|entry| entry.path != folder.entry_path);
+        playlist.entries.push(PlaylistEntry {
+            path: folder.entry_path,
+            media_token: media_token.to_string(),
+            ordering: 0,
+        });
+        playlist.entries.sort_by(|a, b| natural_cmp(&a.path, &b.path));
+
+        for (index, entry) in playlist.entries.iter_mut().enumerate() {
+            let ordering = index + 1;
+            if entry.ordering != ordering {
+                set_ordering(client, credentials, &playlist.token, &entry.media_token, ordering).await?;
+                entry.ordering = ordering;
+            }
+        }
+        self.store.set(&folder.key, playlist);
+        Ok(())
+    }
+
+    async fn create(&self, client: &Client, credentials: &Credentials, folder: &PlaylistFolder, username: &str) -> Result<Playlist, UploadError> {
+        let form = multipart::Form::new()
+            .text("title", folder.title.clone())
+            .text("description", String::new());
+
+        let response = credentials
+            .apply(client.post(playlists_url()))
+            .multipart(form)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(UploadError::Rejected(response.status().as_u16()));
+        }
+
+        // The response has no token of its own, only the API URL of the playlist
+        let body: Value = response.json().await?;
+        let token = body["api_url"]
+            .as_str()
+            .and_then(|url| url.trim_end_matches('/').rsplit('/').next())
+            .ok_or(UploadError::MissingMedia)?
+            .to_string();
+
+        let playlist = Playlist {
+            token,
+            username: username.to_string(),
+            entries: vec![],
+        };
+        // Stored right away, so a crash before the first media is added does not lead to a duplicate
+        self.store.set(&folder.key, playlist.clone());
+        Ok(playlist)
+    }
+
+    fn playlist_folder(&self, relative_path: &str) -> Option<PlaylistFolder> {
+        let components: Vec<&str> = relative_path
+            .split('/')
+            .filter(|component| !component.is_empty())
+            .collect();
+        // The user folder, the folders down to the playlist folder, and at least the file itself
+        if components.len() < self.depth + 2 {
+            return None;
+        }
+        Some(PlaylistFolder {
+            key: components[..=self.depth].join("/"),
+            title: components[self.depth].to_string(),
+            entry_path: components[self.depth + 1..].join("/"),
+        })
+    }
+}
+
+fn playlists_url() -> String {
+    format!("{}/api/v1/playlists", api::base_url())
+}
+
+/// Returns false if the playlist no longer exists
+async fn add_media(client: &Client, credentials: &Credentials, playlist_token: &str, media_token: &str) -> Result<bool, UploadError> {
+    let form = multipart::Form::new()
+        .text("type", "add")
+        .text("media_friendly_token", media_token.to_string());
+
+    let response = credentials
+        .apply(client.put(format!("{}/{}", playlists_url(), playlist_token)))
+        .multipart(form)
+        .send()
+        .await?;
+    match response.status() {
+        StatusCode::NOT_FOUND => Ok(false),
+        status if status.is_success() => Ok(true),
+        status => Err(UploadError::Rejected(status.as_u16())),
+    }
+}
+
+async fn set_ordering(
+    client: &Client,
+    credentials: &Credentials,
+    playlist_token: &str,
+    media_token: &str,
+    ordering: usize,
+) -> Result<(), UploadError> {
+    let form = multipart::Form::new()
+        .text("type", "ordering")
+        .text("media_friendly_token", media_token.to_string())
+        .text("ordering", ordering.to_string());
+
+    let response = credentials
+        .apply(client.put(format!("{}/{}", playlists_url(), playlist_token)))
+        .multipart(form)
+        .send()
+        .await?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(UploadError::Rejected(response.status().as_u16()))
+    }
+}
+
+/// Compares paths the way people expect episodes to be sorted, so `Episode 2` comes before `Episode 10`
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a_chars = a.chars().peekable();
+    let mut b_chars = b.chars().peekable();
+    loop {
+        match (a_chars.peek().copied(), b_chars.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a_char), Some(b_char)) if a_char.is_ascii_digit() && b_char.is_ascii_digit() => {
+                let a_number = take_number(&mut a_chars);
+                let b_number = take_number(&mut b_chars);
+                // Compare by value without parsing, so long digit runs can not overflow
+                let a_trimmed = a_number.trim_start_matches('0');
+                let b_trimmed = b_number.trim_start_matches('0');
+                let ordering = a_trimmed.len()
+                    .cmp(&b_trimmed.len())
+                    .then_with(|| a_trimmed.cmp(b_trimmed))
+                    .then_with(|| a_number.len().cmp(&b_number.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(a_char), Some(b_char)) => {
+                let ordering = a_char.to_lowercase().cmp(b_char.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a_chars.next();
+                b_chars.next();
+            }
+        }
+    }
+}
+
+fn take_number(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+    let mut number = String::new();
+    while let Some(digit) = chars.next_if(|character| character.is_ascii_digit()) {
+        number.push(digit);
+    }
+    number
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_are_compared_by_value() {
+        assert_eq!(natural_cmp("Episode 2", "Episode 10"), Ordering::Less);
+        assert_eq!(natural_cmp("Episode 10", "Episode 9"), Ordering::Greater);
+        assert_eq!(natural_cmp("s1e2", "s1e02"), Ordering::Less);
+        assert_eq!(natural_cmp("Episode 99999999999999999999999", "Episode 100000000000000000000000"), Ordering::Less);
+    }
+
+    #[test]
+    fn text_is_compared_without_case() {
+        assert_eq!(natural_cmp("episode 1", "Episode 1"), Ordering::Equal);
+        assert_eq!(natural_cmp("Alpha", "beta"), Ordering::Less);
+        assert_eq!(natural_cmp("Part", "Part 1"), Ordering::Less);
+    }
+
+    #[test]
+    fn sorting_puts_episodes_in_order() {
+        let mut names = vec!["Ep 10.mp4", "Ep 1.mp4", "ep 2.mp4", "Ep 21.mp4", "Ep 3.mp4"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, vec!["Ep 1.mp4", "ep 2.mp4", "Ep 3.mp4", "Ep 10.mp4", "Ep 21.mp4"]);
+    }
+}
@@ -0,0 +1,182 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use reqwest::{Client, multipart};
+use serde_json::Value;
+use sqlx::{Pool, Postgres};
+use tokio::process::Command;
+use crate::api;
+use crate::auth::Credentials;
+use crate::config::{PosterConfig, PosterFrameConfig};
+use crate::db;
+use crate::path_data::{PathData, UploadError};
+
+const POSTER_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+/// Names of posters shared by every video in a folder, checked after the poster named like the video
+const FOLDER_POSTERS: [&str; 2] = ["poster", "folder"];
+
+/// Finds a poster image next to the video: `<name>.jpg`, then `poster.jpg`, then `folder.jpg`.
+/// Names are compared without case, and `.jpeg` and `.png` work as well.
+pub fn find_poster(video_path: &Path) -> Option<PathBuf> {
+    let directory = video_path.parent()?;
+    let video_stem = video_path.file_stem()?.to_string_lossy().to_lowercase();
+    let images: Vec<(String, PathBuf)> = fs::read_dir(directory)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| POSTER_EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str()))
+        })
+        .filter_map(|path| Some((path.file_stem()?.to_string_lossy().to_lowercase(), path)))
+        .collect();
+
+    let mut names = vec![video_stem.as_str()];
+    names.extend(FOLDER_POSTERS);
+    names
+        .into_iter()
+        .find_map(|name| images.iter().find(|(stem, _)| stem == name).map(|(_, path)| path.clone()))
+}
+
+/// Grabs a frame from the video with ffmpeg into a temporary file, which the caller removes
+async fn generate_poster(video_path: &Path, config: &PosterFrameConfig) -> Option<PathBuf> {
+    let digest = md5::compute(video_path.to_string_lossy().as_bytes());
+    let output = env::temp_dir().join(format!("media_uploader_poster_{:x}.jpg", digest));
+
+    if config.scene_detection {
+        let filter = format!("select='gt(scene,{})'", config.scene_threshold);
+        let grabbed = run_ffmpeg(&["-i", &video_path.to_string_lossy(), "-vf", &filter, "-vsync", "vfr"], &output).await;
+        if grabbed {
+            return Some(output);
+        }
+    }
+    let offset = config.offset_secs.to_string();
+    // Seeking before the input is fast, and an offset past the end gives no frame at all
+    let grabbed = run_ffmpeg(&["-ss", &offset, "-i", &video_path.to_string_lossy()], &output).await;
+    grabbed.then_some(output)
+}
+
+async fn run_ffmpeg(input_args: &[&str], output: &Path) -> bool {
+    let _ = fs::remove_file(output);
+    let status = Command::new("ffmpeg")
+        .args(["-v", "error", "-y"])
+        .args(input_args)
+        .args(["-frames:v", "1", "-q:v", "2"])
+        .arg(output)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await;
+    status.is_ok_and(|status| status.success()) && output.is_file()
+}
+
+/// Sets a poster on uploaded media through the MediaCMS edit page, as the API can not take one
+pub struct PosterUploader {
+    frame: Option<PosterFrameConfig>,
+}
+
+impl PosterUploader {
+    pub fn new(config: &PosterConfig) -> PosterUploader {
+        PosterUploader {
+            frame: config.generate.clone(),
+        }
+    }
+
+    pub async fn attach(
+        &self,
+        client: &Client,
+        credentials: &Credentials,
+        pool: Option<&Pool<Postgres>>,
+        data: &PathData,
+        media_token: &str,
+    ) -> Result<(), UploadError> {
+        let video_path = Path::new(&data.absolute_path);
+        let (poster_path, generated) = match find_poster(video_path) {
+            Some(path) => (path, false),
+            None => match &self.frame {
+                Some(frame) => match generate_poster(video_path, frame).await {
+                    Some(path) => (path, true),
+                    None => return Ok(()),
+                },
+                None => return Ok(()),
+            },
+        };
+
+        let result = self.send(client, credentials, pool, data, media_token, &poster_path).await;
+        if generated {
+            let _ = fs::remove_file(&poster_path);
+        }
+        result
+    }
+
+    async fn send(
+        &self,
+        client: &Client,
+        credentials: &Credentials,
+        pool: Option<&Pool<Postgres>>,
+        data: &PathData,
+        media_token: &str,
+        poster_path: &Path,
+    ) -> Result<(), UploadError> {
+        // The edit page saves every field it has, so the current values are sent back unchanged
+        let api_url = env::var("API_URL").expect("API_URL must be set");
+        let response = credentials
+            .apply(client.get(format!("{}/{}", api_url.trim_end_matches('/'), media_token)))
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(UploadError::Rejected(response.status().as_u16()));
+        }
+        let media: Value = response.json().await?;
+        let category_titles = titles(&media["categories_info"]);
+        let category_ids = match pool {
+            Some(pool) if !category_titles.is_empty() => db::get_category_ids(pool, &category_titles).await?,
+            _ => vec![],
+        };
+
+        let is_png = poster_path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("png"));
+        let poster_part = multipart::Part::bytes(tokio::fs::read(poster_path).await?)
+            .file_name(if is_png { "poster.png" } else { "poster.jpg" })
+            .mime_str(if is_png { "image/png" } else { "image/jpeg" })?;
+
+        let mut form = multipart::Form::new()
+            .text("title", media["title"].as_str().unwrap_or(&data.filename).to_string())
+            .text("description", media["description"].as_str().unwrap_or("").to_string())
+            .text("state", media["state"].as_str().unwrap_or("public").to_string())
+            .text("new_tags", titles(&media["tags_info"]).join(","))
+            .part("uploaded_poster", poster_part);
+        // Unchecked boxes are left out of a form entirely
+        for field in ["enable_comments", "allow_download"] {
+            if media[field].as_bool().unwrap_or(true) {
+                form = form.text(field, "on");
+            }
+        }
+        for category_id in category_ids {
+            form = form.text("category", category_id.to_string());
+        }
+
+        let response = credentials
+            .apply(client.post(format!("{}/edit?m={}", api::base_url(), media_token)))
+            .multipart(form)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(UploadError::Rejected(response.status().as_u16()));
+        }
+        if api::redirected_to_login(&response) {
+            return Err(UploadError::Rejected(401));
+        }
+        Ok(())
+    }
+}
+
+/// Titles of the tags or categories in a media response
+fn titles(items: &Value) -> Vec<String> {
+    items
+        .as_array()
+        .map(|items| items.iter().filter_map(|item| item["title"].as_str().map(String::from)).collect())
+        .unwrap_or_default()
+}
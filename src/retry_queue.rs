@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+use rand::Rng;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use crate::config::RetryConfig;
+use crate::path_data::UploadError;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RetryEntry {
+    pub attempts: u32,
+    pub last_status: u16,
+    /// When the file may be uploaded again. Only set for files that failed during this run.
+    #[serde(skip)]
+    pub next_attempt: Option<Instant>,
+}
+
+pub enum RetryDecision {
+    /// The file has been queued and will be uploaded again after the backoff
+    Retry,
+    /// The failure is permanent or the file has used up all its attempts
+    GiveUp,
+}
+
+/// Files that failed with a transient error, keyed by absolute path. The queue is mirrored to disk
+/// so files that were still waiting, or ran out of attempts, are picked up first by the next run.
+pub struct RetryQueue {
+    file_path: String,
+    config: RetryConfig,
+    entries: Mutex<HashMap<String, RetryEntry>>,
+}
+
+impl RetryQueue {
+    pub fn load(config: &RetryConfig) -> RetryQueue {
+        let mut entries: HashMap<String, RetryEntry> = fs::read_to_string(&config.queue_file)
+            .ok()
+            .and_then(|contents| serde_json::from_str(&contents).ok())
+            .unwrap_or_default();
+        // Leftovers from an earlier run get a fresh set of attempts
+        for entry in entries.values_mut() {
+            entry.attempts = 0;
+        }
+        RetryQueue {
+            file_path: config.queue_file.clone(),
+            config: config.clone(),
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Paths left over from the previous run
+    pub fn leftover_paths(&self) -> Vec<String> {
+        self.entries.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Queues the file for another attempt if the status is transient and attempts remain
+    pub fn schedule(&self, path: &str, status: u16) -> RetryDecision {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(path.to_string()).or_insert(RetryEntry {
+            attempts: 0,
+            last_status: status,
+            next_attempt: None,
+        });
+        entry.attempts += 1;
+        entry.last_status = status;
+
+        let decision = if entry.attempts < self.config.max_attempts {
+            entry.next_attempt = Some(Instant::now() + backoff(&self.config, entry.attempts));
+            RetryDecision::Retry
+        } else {
+            // Keep the entry on disk so the next run tries it first
+            entry.next_attempt = None;
+            RetryDecision::GiveUp
+        };
+        self.save(&entries);
+        decision
+    }
+
+    pub fn remove(&self, path: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.remove(path).is_some() {
+            self.save(&entries);
+        }
+    }
+
+    /// Takes every file that is waiting for another attempt in this run, with the time it is due
+    pub fn take_pending(&self) -> Vec<(String, Instant)> {
+        let mut entries = self.entries.lock().unwrap();
+        entries
+            .iter_mut()
+            .filter_map(|(path, entry)| entry.next_attempt.take().map(|due| (path.clone(), due)))
+            .collect()
+    }
+
+    fn save(&self, entries: &HashMap<String, RetryEntry>) {
+        if let Ok(serialized) = serde_json::to_string_pretty(entries) {
+            let _ = fs::write(&self.file_path, serialized);
+        }
+    }
+}
+
+/// Exponential backoff with jitter, so files that failed together do not retry together
+pub fn backoff(config: &RetryConfig, attempts: u32) -> Duration {
+    let exponent = attempts.saturating_sub(1).min(16);
+    let delay = config.base_delay_secs
+        .saturating_mul(1 << exponent)
+        .min(config.max_delay_secs) as f64;
+    let jittered = rand::thread_rng().gen_range(delay / 2.0..=delay.max(0.001));
+    Duration::from_secs_f64(jittered)
+}
+
+/// Whether an upload that got a response with this status is worth trying again
+pub fn is_retryable_status(status: u16) -> bool {
+    matches!(
+        StatusCode::from_u16(status),
+        Ok(StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT)
+    )
+}
+
+/// Whether an upload that failed without a usable response is worth trying again
+pub fn is_retryable_error(error: &UploadError) -> bool {
+    match error {
+        UploadError::Request(error) => {
+            if let Some(status) = error.status() {
+                return is_retryable_status(status.as_u16());
+            }
+            if error.is_timeout() || error.is_connect() {
+                return true;
+            }
+            // Connection resets are buried somewhere in the source chain
+            let mut source = error.source();
+            while let Some(inner) = source {
+                if let Some(io_error) = inner.downcast_ref::<io::Error>() {
+                    if matches!(
+                        io_error.kind(),
+                        io::ErrorKind::ConnectionReset
+                            | io::ErrorKind::ConnectionAborted
+                            | io::ErrorKind::BrokenPipe
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::UnexpectedEof
+                    ) {
+                        return true;
+                    }
+                }
+                source = inner.source();
+            }
+            false
+        }
+        UploadError::Rejected(status_code) => is_retryable_status(*status_code),
+        UploadError::Stalled => true,
+        // The media already exists, so uploading it again would only create a duplicate
+        UploadError::Io(_) | UploadError::Database(_) | UploadError::MissingMedia => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn retry_config() -> RetryConfig {
+        RetryConfig {
+            base_delay_secs: 10,
+            max_delay_secs: 60,
+            ..RetryConfig::default()
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_with_jitter() {
+        let config = retry_config();
+        for (attempts, delay) in [(1, 10.0), (2, 20.0), (3, 40.0)] {
+            for _ in 0..20 {
+                let backoff = backoff(&config, attempts).as_secs_f64();
+                assert!((delay / 2.0..=delay).contains(&backoff), "{} after {} attempts", backoff, attempts);
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let config = retry_config();
+        for attempts in [4, 10, 100, u32::MAX] {
+            let backoff = backoff(&config, attempts).as_secs_f64();
+            assert!((30.0..=60.0).contains(&backoff), "{} after {} attempts", backoff, attempts);
+        }
+    }
+
+    #[test]
+    fn only_gateway_errors_are_retried() {
+        assert!(is_retryable_status(502));
+        assert!(is_retryable_status(503));
+        assert!(is_retryable_status(504));
+        assert!(!is_retryable_status(400));
+        assert!(!is_retryable_status(500));
+    }
+}
@@ -0,0 +1,115 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use serde::{Deserialize, Serialize};
+
+/// A credential that never shows up in logs or the status screen. Use `expose` only where the value
+/// is sent to the server.
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Secret {
+        Secret(value)
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Secret([REDACTED])")
+    }
+}
+
+impl Display for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[REDACTED]")
+    }
+}
+
+/// Where the secrets in `config.yml` are read from
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SecretSource {
+    /// An environment variable, or a line in `.env`, with the name of the secret
+    #[default]
+    Env,
+    /// A file with the name of the secret inside `path`, like Docker secrets or systemd credentials
+    Directory { path: String },
+    /// The stdout of a command like `pass` or `vault`. `{name}` in the arguments is replaced with the
+    /// name of the secret.
+    Command { command: String, #[serde(default)] args: Vec<String> },
+}
+
+pub trait SecretProvider: Send + Sync {
+    /// Looks up a secret such as `ERIK_PASSWORD`
+    fn get(&self, name: &str) -> Result<Secret, String>;
+}
+
+pub struct EnvSecretProvider;
+
+impl SecretProvider for EnvSecretProvider {
+    fn get(&self, name: &str) -> Result<Secret, String> {
+        env::var(name)
+            .map(Secret::new)
+            .map_err(|_| format!("{} is not set", name))
+    }
+}
+
+pub struct DirectorySecretProvider {
+    directory: PathBuf,
+}
+
+impl SecretProvider for DirectorySecretProvider {
+    fn get(&self, name: &str) -> Result<Secret, String> {
+        let path = self.directory.join(name);
+        fs::read_to_string(&path)
+            .map(|contents| Secret::new(contents.trim_end_matches(['\r', '\n']).to_string()))
+            .map_err(|error| format!("Could not read secret file {:?}: {}", path, error))
+    }
+}
+
+pub struct CommandSecretProvider {
+    command: String,
+    args: Vec<String>,
+}
+
+impl SecretProvider for CommandSecretProvider {
+    fn get(&self, name: &str) -> Result<Secret, String> {
+        let output = Command::new(&self.command)
+            .args(self.args.iter().map(|arg| arg.replace("{name}", name)))
+            .output()
+            .map_err(|error| format!("Could not run {} for {}: {}", self.command, name, error))?;
+
+        // Neither stdout nor stderr is included in the error, as either may contain the secret
+        if !output.status.success() {
+            return Err(format!("{} exited with {} for {}", self.command, output.status, name));
+        }
+        let secret = String::from_utf8(output.stdout)
+            .map_err(|_| format!("{} returned a secret for {} that is not UTF-8", self.command, name))?;
+        // Tools like `pass` print the secret on the first line
+        let secret = secret.lines().next().unwrap_or("").to_string();
+        if secret.is_empty() {
+            return Err(format!("{} returned an empty secret for {}", self.command, name));
+        }
+        Ok(Secret::new(secret))
+    }
+}
+
+pub fn create_secret_provider(source: &SecretSource) -> Box<dyn SecretProvider> {
+    match source {
+        SecretSource::Env => Box::new(EnvSecretProvider),
+        SecretSource::Directory { path } => Box::new(DirectorySecretProvider {
+            directory: PathBuf::from(path),
+        }),
+        SecretSource::Command { command, args } => Box::new(CommandSecretProvider {
+            command: command.clone(),
+            args: args.clone(),
+        }),
+    }
+}
@@ -1,18 +1,55 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use crossterm::style::Stylize;
+use tokio::sync::oneshot;
 use tokio::time::Instant;
+use crate::file_extension::FileExtension;
 use crate::upload_status::UploadStatus;
 
+/// Weight of a new sample in the moving average of the upload rate
+const RATE_SMOOTHING: f64 = 0.3;
+
+pub struct UploadingFile {
+    pub(crate) start_time: Instant,
+    pub(crate) path: String,
+    pub(crate) total_bytes: u64,
+    /// Updated by the upload body as bytes are handed to the connection
+    pub(crate) sent_bytes: Arc<AtomicU64>,
+    last_sample: (Instant, u64),
+    /// Bytes per second
+    rate: f64,
+}
+
 pub struct SharedState {
     pub(crate) files_retrieved: usize,
     pub(crate) uploaded_files: i32,
     pub(crate) corrupt_files_counter: i32,
     pub(crate) remaining_files: i32,
     pub(crate) failed_files_counter: i32,
+    pub(crate) retried_files_counter: i32,
+    pub(crate) encode_failed_counter: i32,
+    /// Files that have been uploaded and whose encoding is being followed
+    pub(crate) awaiting_encoding: Vec<String>,
     pub(crate) skipped_files: i32,
+    pub(crate) upload_limit: usize,
+    pub(crate) max_upload_limit: usize,
+    pub(crate) encoding_backlog: Option<(i64, i64)>,
+    pub(crate) uploads_paused: bool,
     pub(crate) last_processed_files: Vec<(UploadStatus, String)>,
-    pub(crate) currently_uploading: Vec<(Instant, String)>,
+    pub(crate) currently_uploading: Vec<UploadingFile>,
+    /// Size of every file that has not been processed yet and is expected to be uploaded, keyed by path
+    pub(crate) remaining_file_sizes: HashMap<String, u64>,
+    pub(crate) remaining_bytes: u64,
     pub(crate) corrupt_files: Vec<(UploadStatus, String)>,
     pub(crate) failed_files: Vec<(UploadStatus, String)>,
+    pub(crate) encode_failed_files: Vec<(UploadStatus, String)>,
+    /// Notified when the file with this path is done
+    pub(crate) outcome_listeners: HashMap<String, oneshot::Sender<UploadStatus>>,
+    /// Uploaded files whose media is missing steps like its playlist, as (path, error, whether it is retried)
+    pub(crate) incomplete_media: Vec<(String, String, bool)>,
+    /// Files whose content is a different container than the extension says, as (path, extension, content)
+    pub(crate) mismatched_files: Vec<(String, FileExtension, FileExtension)>,
 }
 
 impl SharedState {
@@ -26,6 +63,10 @@ impl SharedState {
         self.failed_files_counter += 1;
     }
 
+    fn increment_retried_files(&mut self) {
+        self.retried_files_counter += 1;
+    }
+
     fn increment_skipped_files(&mut self) {
         self.skipped_files += 1;
     }
@@ -39,8 +80,13 @@ impl SharedState {
         if self.last_processed_files.len() > 20 {
             self.last_processed_files.remove(0);
         }
+        if !matches!(content.0, UploadStatus::Retrying(_) | UploadStatus::Reuploading) {
+            if let Some(listener) = self.outcome_listeners.remove(&content.1) {
+                let _ = listener.send(content.0);
+            }
+        }
         match &content.0 {
-            UploadStatus::Skipped => {
+            UploadStatus::Skipped | UploadStatus::Duplicate | UploadStatus::LikelyDuplicate => {
                 self.increment_skipped_files()
             }
             UploadStatus::Failed(reason) => {
@@ -49,19 +95,80 @@ impl SharedState {
             UploadStatus::Corrupt => {
                 self.append_to_corrupt_files(content.clone().1)
             }
+            UploadStatus::Retrying(_) | UploadStatus::Reuploading => {
+                // The file is not done yet, so it still counts as remaining
+                self.increment_retried_files();
+                return;
+            }
             UploadStatus::Success => {
                 self.increment_uploaded_files()
             }
+            UploadStatus::EncodeFailed => {
+                self.append_to_encode_failed_files(content.clone().1)
+            }
         }
         self.decrement_remaining_files();
+        if let Some(size) = self.remaining_file_sizes.remove(&content.1) {
+            self.remaining_bytes -= size;
+        }
+    }
+
+    /// Tells once the file is done, with the status it ended with
+    pub(crate) fn watch_outcome(&mut self, path: &str) -> oneshot::Receiver<UploadStatus> {
+        let (sender, receiver) = oneshot::channel();
+        self.outcome_listeners.insert(path.to_string(), sender);
+        receiver
     }
 
     pub(crate) fn set_initial_remaining_files(&mut self, number: i32) {
         self.remaining_files = number;
     }
 
-    pub(crate) fn append_to_currently_uploading(&mut self, path: String) {
-        self.currently_uploading.push((Instant::now(), path))
+    pub(crate) fn set_remaining_file_sizes(&mut self, sizes: HashMap<String, u64>) {
+        self.remaining_bytes = sizes.values().sum();
+        self.remaining_file_sizes = sizes;
+    }
+
+    /// Counts a file that was not expected to be uploaded, once it turns out it needs to be
+    pub(crate) fn add_remaining_file_size(&mut self, path: String, size: u64) {
+        if self.remaining_file_sizes.insert(path, size).is_none() {
+            self.remaining_bytes += size;
+        }
+    }
+
+    /// Returns the counter the upload should add its sent bytes to
+    pub(crate) fn append_to_currently_uploading(&mut self, path: String, total_bytes: u64) -> Arc<AtomicU64> {
+        let sent_bytes = Arc::new(AtomicU64::new(0));
+        let now = Instant::now();
+        self.currently_uploading.push(UploadingFile {
+            start_time: now,
+            path,
+            total_bytes,
+            sent_bytes: sent_bytes.clone(),
+            last_sample: (now, 0),
+            rate: 0.0,
+        });
+        sent_bytes
+    }
+
+    /// Updates the upload rate of every file from the bytes sent since the last sample
+    pub(crate) fn sample_upload_rates(&mut self) {
+        let now = Instant::now();
+        for file in self.currently_uploading.iter_mut() {
+            let (sample_time, sample_bytes) = file.last_sample;
+            let elapsed = now.duration_since(sample_time).as_secs_f64();
+            if elapsed < 1.0 {
+                continue;
+            }
+            let sent_bytes = file.sent_bytes.load(Ordering::Relaxed);
+            let rate = sent_bytes.saturating_sub(sample_bytes) as f64 / elapsed;
+            file.rate = if sample_bytes == 0 && file.rate == 0.0 {
+                rate
+            } else {
+                file.rate + RATE_SMOOTHING * (rate - file.rate)
+            };
+            file.last_sample = (now, sent_bytes);
+        }
     }
 
     pub(crate) fn append_to_corrupt_files(&mut self, path: String) {
@@ -74,6 +181,46 @@ impl SharedState {
         self.increment_failed_files();
     }
 
+    pub(crate) fn append_to_encode_failed_files(&mut self, path: String) {
+        self.encode_failed_files.push((UploadStatus::EncodeFailed, path));
+        self.encode_failed_counter += 1;
+    }
+
+    /// Moves an uploaded file from the uploads to the files waiting for their encoding
+    pub(crate) fn start_awaiting_encoding(&mut self, path: String) {
+        self.remove_from_currently_uploading(path.clone());
+        self.awaiting_encoding.push(path);
+    }
+
+    pub(crate) fn stop_awaiting_encoding(&mut self, path: &str) {
+        self.awaiting_encoding.retain(|awaiting| awaiting != path);
+    }
+
+    pub(crate) fn append_to_incomplete_media(&mut self, path: String, error: String, retrying: bool) {
+        self.remove_from_incomplete_media(&path);
+        self.incomplete_media.push((path, error, retrying));
+    }
+
+    pub(crate) fn remove_from_incomplete_media(&mut self, path: &str) {
+        self.incomplete_media.retain(|(incomplete, _, _)| incomplete != path);
+    }
+
+    pub(crate) fn append_to_mismatched_files(&mut self, path: String, extension: FileExtension, container: FileExtension) {
+        if !self.mismatched_files.iter().any(|(mismatched, _, _)| *mismatched == path) {
+            self.mismatched_files.push((path, extension, container));
+        }
+    }
+
+    pub(crate) fn set_upload_limit(&mut self, limit: usize, max_limit: usize) {
+        self.upload_limit = limit;
+        self.max_upload_limit = max_limit;
+    }
+
+    pub(crate) fn set_encoding_backlog(&mut self, pending: i64, running: i64, paused: bool) {
+        self.encoding_backlog = Some((pending, running));
+        self.uploads_paused = paused;
+    }
+
     pub(crate) fn set_files_retrieved(&mut self, amount: usize) {
         self.files_retrieved = amount;
     }
@@ -82,27 +229,66 @@ impl SharedState {
         let index = self
             .currently_uploading
             .iter()
-            .position(|(_, x)| *x == path)
+            .position(|file| file.path == path)
             .unwrap();
         self.currently_uploading.remove(index);
     }
 
     pub(crate) fn print_status(&self) {
         println!("Files in database: {}", self.files_retrieved);
+        println!("Upload limit: {}/{}", self.upload_limit, self.max_upload_limit);
+        if let Some((pending, running)) = self.encoding_backlog {
+            print!("Encoding backlog: {} pending, {} running", pending, running);
+            if self.uploads_paused {
+                print!(" {}", "- new uploads paused".yellow());
+            }
+            println!();
+        }
+
+        let throughput: f64 = self.currently_uploading.iter().map(|file| file.rate).sum();
+        let in_flight_bytes: u64 = self.currently_uploading
+            .iter()
+            .map(|file| file.sent_bytes.load(Ordering::Relaxed))
+            .sum();
+        let bytes_left = self.remaining_bytes.saturating_sub(in_flight_bytes);
+        let eta = if throughput > 0.0 {
+            format_duration((bytes_left as f64 / throughput) as u64)
+        } else {
+            String::from("--:--:--")
+        };
+        println!("Throughput: {}/s, Remaining: {}, ETA: {}",
+                 format_bytes(throughput as u64),
+                 format_bytes(bytes_left),
+                 eta
+        );
+
         println!("Currently uploading:");
 
-        for (start_time, path) in self.currently_uploading.clone().iter().rev() {
-            let elapsed = start_time.elapsed();
-            let hours = elapsed.as_secs() / 3600;
-            let minutes = (elapsed.as_secs() % 3600) / 60;
-            let seconds = elapsed.as_secs() % 60;
-            println!("{:02}:{:02}:{:02}\t {}", hours, minutes, seconds, path)
+        for file in self.currently_uploading.iter().rev() {
+            let sent_bytes = file.sent_bytes.load(Ordering::Relaxed);
+            let percent = if file.total_bytes > 0 {
+                sent_bytes as f64 / file.total_bytes as f64 * 100.0
+            } else {
+                100.0
+            };
+            println!("{}\t {:5.1}% {:>10}/s\t {}",
+                     format_duration(file.start_time.elapsed().as_secs()),
+                     percent,
+                     format_bytes(file.rate as u64),
+                     file.path
+            )
+        }
+
+        if !self.awaiting_encoding.is_empty() {
+            println!("Waiting for encoding: {}", self.awaiting_encoding.len());
         }
 
-        println!("\nUploaded files: {}, Corrupt files: {}, Failed files: {}, Skipped files: {}, Remaining files: {}\n",
+        println!("\nUploaded files: {}, Corrupt files: {}, Failed files: {}, Encode failures: {}, Retries: {}, Skipped files: {}, Remaining files: {}\n",
                  self.uploaded_files,
                  self.corrupt_files_counter,
                  self.failed_files_counter,
+                 self.encode_failed_counter,
+                 self.retried_files_counter,
                  self.skipped_files,
                  self.remaining_files
         );
@@ -124,5 +310,45 @@ impl SharedState {
         for (status_code, path) in self.failed_files.clone().iter().rev() {
             println!("{}     \t\t {}", status_code, path)
         }
+
+        if !self.encode_failed_files.is_empty() {
+            println!("\nEncode failures:");
+            for (_, path) in self.encode_failed_files.iter().rev() {
+                println!("\t\t\t {}", path)
+            }
+        }
+
+        if !self.incomplete_media.is_empty() {
+            println!("\nUploaded, but steps after the upload failed:");
+            for (path, error, retrying) in self.incomplete_media.iter().rev() {
+                let state = if *retrying { "RETRY".yellow() } else { "FAILED".red() };
+                println!("{}\t\t {}\n\t\t\t {}", state, path, error)
+            }
+        }
+
+        if !self.mismatched_files.is_empty() {
+            println!("\nExtension does not match content:");
+            for (path, extension, container) in self.mismatched_files.iter().rev() {
+                println!("{} extension, {} content\t {}", extension, container, path)
+            }
+        }
+    }
+}
+
+fn format_duration(total_seconds: u64) -> String {
+    let hours = total_seconds / 3600;
+    let minutes = (total_seconds % 3600) / 60;
+    let seconds = total_seconds % 60;
+    format!("{:02}:{:02}:{:02}", hours, minutes, seconds)
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
     }
+    format!("{:.1} {}", value, UNITS[unit])
 }
@@ -0,0 +1,185 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use reqwest::{Client, multipart};
+use sqlx::{Pool, Postgres};
+use crate::api;
+use crate::auth::Credentials;
+use crate::config::SubtitleConfig;
+use crate::db;
+use crate::path_data::{PathData, UploadError};
+
+const SUBTITLE_EXTENSIONS: [&str; 2] = ["srt", "vtt"];
+
+/// A subtitle file next to a video, like `talk.en.srt` next to `talk.mp4`
+#[derive(Debug, Clone)]
+pub struct SubtitleFile {
+    pub path: PathBuf,
+    /// Language code from the filename, like `en`
+    pub language: String,
+}
+
+impl SubtitleFile {
+    /// The subtitles as WebVTT, converting from SRT if needed
+    pub fn read_as_vtt(&self) -> io::Result<String> {
+        let contents = fs::read_to_string(&self.path)?;
+        let contents = contents.trim_start_matches('\u{feff}').replace("\r\n", "\n");
+        if contents.starts_with("WEBVTT") {
+            return Ok(contents);
+        }
+        Ok(srt_to_vtt(&contents))
+    }
+
+    fn vtt_filename(&self) -> String {
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        format!("{}.vtt", stem)
+    }
+}
+
+/// Finds the subtitle sidecars of a video. The language is the last part of the name between the
+/// video name and the extension, or `default_language` if there is none.
+pub fn find_subtitles(video_path: &Path, default_language: Option<&str>) -> Vec<SubtitleFile> {
+    let (Some(directory), Some(video_stem)) = (video_path.parent(), video_path.file_stem()) else {
+        return vec![];
+    };
+    let video_stem = video_stem.to_string_lossy();
+    let Ok(entries) = fs::read_dir(directory) else {
+        return vec![];
+    };
+
+    let mut subtitles: Vec<SubtitleFile> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .filter_map(|path| {
+            let extension = path.extension()?.to_string_lossy().to_lowercase();
+            if !SUBTITLE_EXTENSIONS.contains(&extension.as_str()) {
+                return None;
+            }
+            let stem = path.file_stem()?.to_string_lossy().to_string();
+            let language = subtitle_language(&stem, &video_stem, default_language)?;
+            Some(SubtitleFile { path, language })
+        })
+        .collect();
+    subtitles.sort_by(|a, b| a.path.cmp(&b.path));
+    subtitles
+}
+
+/// The language of a subtitle file named `stem`, or `None` if it does not belong to the video
+fn subtitle_language(stem: &str, video_stem: &str, default_language: Option<&str>) -> Option<String> {
+    if stem == video_stem {
+        return default_language.map(String::from);
+    }
+    let suffix = stem.strip_prefix(video_stem)?.strip_prefix('.')?;
+    suffix.rsplit('.').next().map(String::from)
+}
+
+/// Adds the subtitles next to a video to its media through the MediaCMS subtitle form
+pub struct SubtitleUploader {
+    default_language: Option<String>,
+}
+
+impl SubtitleUploader {
+    pub fn new(config: &SubtitleConfig) -> SubtitleUploader {
+        SubtitleUploader {
+            default_language: config.default_language.clone(),
+        }
+    }
+
+    /// Languages are looked up in the database, as the form takes their id. Subtitles in a language
+    /// MediaCMS does not know are left out.
+    pub async fn attach(
+        &self,
+        client: &Client,
+        credentials: &Credentials,
+        pool: &Pool<Postgres>,
+        data: &PathData,
+        media_token: &str,
+    ) -> Result<(), UploadError> {
+        let video_path = Path::new(&data.absolute_path);
+        for subtitle in find_subtitles(video_path, self.default_language.as_deref()) {
+            let Some(language_id) = db::get_language_id(pool, &subtitle.language).await? else {
+                continue;
+            };
+            let subtitle_part = multipart::Part::text(subtitle.read_as_vtt()?)
+                .file_name(subtitle.vtt_filename())
+                .mime_str("text/vtt")?;
+            let form = multipart::Form::new()
+                .text("language", language_id.to_string())
+                .part("subtitle_file", subtitle_part);
+
+            let response = credentials
+                .apply(client.post(format!("{}/add_subtitle?m={}", api::base_url(), media_token)))
+                .multipart(form)
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(UploadError::Rejected(response.status().as_u16()));
+            }
+            if api::redirected_to_login(&response) {
+                return Err(UploadError::Rejected(401));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// SRT and WebVTT only differ in the header and the decimal separator of the timestamps
+fn srt_to_vtt(srt: &str) -> String {
+    let mut vtt = String::from("WEBVTT\n\n");
+    for line in srt.lines() {
+        if line.contains("-->") {
+            vtt.push_str(&line.replace(',', "."));
+        } else {
+            vtt.push_str(line);
+        }
+        vtt.push('\n');
+    }
+    vtt
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+    use super::*;
+
+    #[test]
+    fn srt_to_vtt_adds_header_and_fixes_timestamps() {
+        let srt = "1\n00:00:01,000 --> 00:00:02,500\nHello, world\n\n2\n00:00:03,000 --> 00:00:04,000\nBye\n";
+        let expected = "WEBVTT\n\n1\n00:00:01.000 --> 00:00:02.500\nHello, world\n\n2\n00:00:03.000 --> 00:00:04.000\nBye\n";
+        assert_eq!(srt_to_vtt(srt), expected);
+    }
+
+    #[test]
+    fn language_is_last_part_after_video_name() {
+        assert_eq!(subtitle_language("talk.en", "talk", None), Some(String::from("en")));
+        assert_eq!(subtitle_language("talk.forced.de", "talk", None), Some(String::from("de")));
+    }
+
+    #[test]
+    fn language_falls_back_to_default_for_same_name() {
+        assert_eq!(subtitle_language("talk", "talk", Some("en")), Some(String::from("en")));
+        assert_eq!(subtitle_language("talk", "talk", None), None);
+    }
+
+    #[test]
+    fn subtitles_of_other_videos_are_ignored() {
+        assert_eq!(subtitle_language("talk2.en", "talk", None), None);
+        assert_eq!(subtitle_language("other.en", "talk", None), None);
+    }
+
+    #[test]
+    fn find_subtitles_reads_languages_from_sidecars() {
+        let directory = env::temp_dir().join(format!("media_uploader_subtitles_{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        for name in ["talk.mp4", "talk.srt", "talk.en.srt", "talk.de.VTT", "talk.fr.txt", "talk2.es.srt"] {
+            fs::write(directory.join(name), "").unwrap();
+        }
+
+        let subtitles = find_subtitles(&directory.join("talk.mp4"), Some("nl"));
+        let _ = fs::remove_dir_all(&directory);
+
+        let languages: Vec<&str> = subtitles.iter().map(|subtitle| subtitle.language.as_str()).collect();
+        assert_eq!(languages, ["de", "en", "nl"]);
+    }
+}
@@ -0,0 +1,56 @@
+use std::sync::Arc;
+use crate::bandwidth::BandwidthLimiter;
+use crate::categories::CategoryRules;
+use crate::concurrency::AdaptiveLimiter;
+use crate::config::Config;
+use crate::encoding_backlog::EncodingGate;
+use crate::encoding_monitor::EncodingMonitor;
+use crate::follow_ups::FollowUpQueue;
+use crate::hash_cache::HashCache;
+use crate::near_duplicates::NearDuplicateDetector;
+use crate::retry_queue::RetryQueue;
+use crate::upload_target::UploadTarget;
+
+/// Everything an upload task needs besides the file itself, shared between all tasks of a run
+pub struct UploadContext {
+    pub target: Box<dyn UploadTarget>,
+    pub category_rules: CategoryRules,
+    pub retry_queue: RetryQueue,
+    pub follow_ups: FollowUpQueue,
+    pub hash_cache: Arc<HashCache>,
+    pub near_duplicates: Option<NearDuplicateDetector>,
+    pub limiter: AdaptiveLimiter,
+    pub encoding_gate: EncodingGate,
+    pub encoding_monitor: Option<EncodingMonitor>,
+    pub bandwidth: Arc<BandwidthLimiter>,
+}
+
+impl UploadContext {
+    pub fn new(
+        target: Box<dyn UploadTarget>,
+        category_rules: CategoryRules,
+        hash_cache: Arc<HashCache>,
+        config: &Config,
+        encoding_gate: EncodingGate,
+        bandwidth: Arc<BandwidthLimiter>,
+    ) -> UploadContext {
+        let concurrency_limit: usize = config.number_of_threads as usize;
+        let limiter = match &config.adaptive_concurrency {
+            Some(adaptive_config) => AdaptiveLimiter::from_config(concurrency_limit, adaptive_config),
+            None => AdaptiveLimiter::fixed(concurrency_limit),
+        };
+
+        UploadContext {
+            target,
+            category_rules,
+            retry_queue: RetryQueue::load(&config.retry),
+            follow_ups: FollowUpQueue::load(&config.retry),
+            hash_cache,
+            near_duplicates: config.near_duplicates.as_ref().map(NearDuplicateDetector::load),
+            limiter,
+            encoding_gate,
+            encoding_monitor: config.encoding_monitor.as_ref().map(EncodingMonitor::new),
+            bandwidth,
+        }
+    }
+}
@@ -4,18 +4,32 @@ use crossterm::style::{StyledContent, Stylize};
 #[derive(Copy, Clone)]
 pub enum UploadStatus {
     Skipped,
+    /// Another copy of the file in the library is uploaded instead
+    Duplicate,
+    /// Looks like a re-encoded or trimmed copy of media that is already uploaded
+    LikelyDuplicate,
     Failed(u16),
+    Retrying(u16),
+    /// Encoding failed and the file is being uploaded again
+    Reuploading,
     Corrupt,
     Success,
+    /// The upload went through, but the server could not encode the file
+    EncodeFailed,
 }
 
 impl UploadStatus {
     pub fn get_str(self) -> StyledContent<String> {
         match self {
             UploadStatus::Skipped => String::from("SKIPPED").white(),
+            UploadStatus::Duplicate => String::from("DUPLICATE").white(),
+            UploadStatus::LikelyDuplicate => String::from("LIKELY DUPLICATE").yellow(),
             UploadStatus::Failed(reason) => format!("{}", reason).red(),
+            UploadStatus::Retrying(reason) => format!("RETRY {}", reason).yellow(),
+            UploadStatus::Reuploading => String::from("REUPLOAD").yellow(),
             UploadStatus::Corrupt => String::from("CORRUPTED").red(),
-            UploadStatus::Success => String::from("SUCCESS").green()
+            UploadStatus::Success => String::from("SUCCESS").green(),
+            UploadStatus::EncodeFailed => String::from("ENCODE FAILED").red()
         }
     }
 }
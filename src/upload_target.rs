@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use async_trait::async_trait;
+use crate::follow_ups::FollowUpStep;
+use crate::hash_strategy::{HashStrategy, StoredHash};
+use crate::path_data::{PathData, UploadError};
+
+/// Media that was created on a target
+pub struct UploadedMedia {
+    /// How the target refers to the media, like the friendly token in MediaCMS
+    pub id: String,
+}
+
+/// The strategies of the given hashes, each once, leaving out strategies this version does not know
+pub fn strategies_of<'a>(hashes: impl Iterator<Item = &'a StoredHash>) -> Vec<&'static dyn HashStrategy> {
+    let mut strategies: Vec<&'static dyn HashStrategy> = vec![];
+    for strategy in hashes.filter_map(StoredHash::strategy) {
+        if !strategies.iter().any(|known| known.id() == strategy.id()) {
+            strategies.push(strategy);
+        }
+    }
+    strategies
+}
+
+/// Where the encoding of uploaded media stands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncodingStatus {
+    Pending,
+    Success,
+    Failed,
+}
+
+/// Somewhere files can be uploaded to. File traversal only talks to this trait, so new targets can
+/// be added without touching it.
+#[async_trait]
+pub trait UploadTarget: Send + Sync {
+    /// Number of media the target had when the run started
+    fn media_count(&self) -> usize;
+
+    /// How the existing media with this size was hashed, so only possible duplicates are hashed, and
+    /// only in the ways that can match. Empty if no media has this size.
+    fn hash_strategies(&self, username: &str, file_size: u64) -> Vec<&'static dyn HashStrategy>;
+
+    /// Whether media with this size and hash already exists
+    fn contains(&self, username: &str, file_size: u64, hash: &StoredHash) -> bool;
+
+    /// Creates the media from the file, adding the bytes sent to `sent_bytes` as it goes. Once the
+    /// media exists this must succeed, as a failure makes the file be uploaded again.
+    async fn upload(&self, data: &PathData, sent_bytes: &Arc<AtomicU64>) -> Result<UploadedMedia, UploadError>;
+
+    /// Steps that finish new media of this user after the upload, in order
+    fn follow_up_steps(&self, username: &str) -> Vec<FollowUpStep>;
+
+    async fn follow_up(&self, data: &PathData, media: &UploadedMedia, step: FollowUpStep) -> Result<(), UploadError>;
+
+    /// Sets title and tags of media that already exists
+    async fn update_metadata(&self, data: &PathData, media: &UploadedMedia) -> Result<(), UploadError>;
+
+    /// Whether the server has finished encoding the media
+    async fn encoding_status(&self, data: &PathData, media: &UploadedMedia) -> Result<EncodingStatus, UploadError>;
+
+    /// Removes the media from the target
+    async fn delete(&self, data: &PathData, media: &UploadedMedia) -> Result<(), UploadError>;
+}
+
+/// Sends the files of each user to the target configured for them
+pub struct TargetRouter {
+    targets: Vec<Box<dyn UploadTarget>>,
+    /// Index into `targets` for every user, any other user goes to the first target
+    user_targets: HashMap<String, usize>,
+}
+
+impl TargetRouter {
+    pub fn new(targets: Vec<Box<dyn UploadTarget>>, user_targets: HashMap<String, usize>) -> TargetRouter {
+        TargetRouter { targets, user_targets }
+    }
+
+    fn target_for(&self, username: &str) -> &dyn UploadTarget {
+        let index = self.user_targets.get(username).copied().unwrap_or(0);
+        self.targets[index].as_ref()
+    }
+}
+
+#[async_trait]
+impl UploadTarget for TargetRouter {
+    fn media_count(&self) -> usize {
+        self.targets.iter().map(|target| target.media_count()).sum()
+    }
+
+    fn hash_strategies(&self, username: &str, file_size: u64) -> Vec<&'static dyn HashStrategy> {
+        self.target_for(username).hash_strategies(username, file_size)
+    }
+
+    fn contains(&self, username: &str, file_size: u64, hash: &StoredHash) -> bool {
+        self.target_for(username).contains(username, file_size, hash)
+    }
+
+    async fn upload(&self, data: &PathData, sent_bytes: &Arc<AtomicU64>) -> Result<UploadedMedia, UploadError> {
+        self.target_for(&data.username).upload(data, sent_bytes).await
+    }
+
+    fn follow_up_steps(&self, username: &str) -> Vec<FollowUpStep> {
+        self.target_for(username).follow_up_steps(username)
+    }
+
+    async fn follow_up(&self, data: &PathData, media: &UploadedMedia, step: FollowUpStep) -> Result<(), UploadError> {
+        self.target_for(&data.username).follow_up(data, media, step).await
+    }
+
+    async fn update_metadata(&self, data: &PathData, media: &UploadedMedia) -> Result<(), UploadError> {
+        self.target_for(&data.username).update_metadata(data, media).await
+    }
+
+    async fn encoding_status(&self, data: &PathData, media: &UploadedMedia) -> Result<EncodingStatus, UploadError> {
+        self.target_for(&data.username).encoding_status(data, media).await
+    }
+
+    async fn delete(&self, data: &PathData, media: &UploadedMedia) -> Result<(), UploadError> {
+        self.target_for(&data.username).delete(data, media).await
+    }
+}